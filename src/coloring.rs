@@ -1,15 +1,25 @@
+//! Count the colors used for nucleotides in R2DT diagrams. The colors show
+//! how each nucleotide relates to the template the diagram was drawn from.
+
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
-use std::iter::Iterator;
-use std::path::PathBuf;
+use std::iter::{self, Iterator};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use globset::{Glob, GlobSetBuilder};
 use walkdir::WalkDir;
 
-use log::{info, trace};
+use log::{info, trace, warn};
 
-use quick_xml::events::Event;
+use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
 
 use flate2::read::GzDecoder;
@@ -18,134 +28,961 @@ use serde::{Deserialize, Serialize};
 
 use anyhow::{anyhow, Result};
 
+use regex::Regex;
+
 use crate::fixups::urs_utils;
 
 use crate::results::JsonDiagram;
+use crate::urs::UrsId;
+
+pub mod score;
+
+/// The number of nucleotides of each color in a single diagram.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all))]
+pub struct Counts {
+    pub urs: UrsId,
+    /// Nucleotides which differ from the template (green).
+    pub changed: u64,
+    /// Nucleotides which match the template (black).
+    pub unchanged: u64,
+    /// Nucleotides inserted relative to the template (red).
+    pub inserted: u64,
+    /// Nucleotides moved from their template position (blue).
+    pub moved: u64,
+    /// Nucleotides rotated from their template position (brown).
+    pub rotated: u64,
+    /// All nucleotides, including other.
+    pub total: u64,
+    // Columns added after the first release default to empty, so older CSV
+    // files can still be read.
+    /// Nucleotides with any other class.
+    #[serde(default)]
+    pub other: u64,
+    /// The classes counted as other, like `purple:2;orange:1`.
+    #[serde(default)]
+    pub other_classes: String,
+    /// The number of nucleotides with each letter, regardless of case.
+    #[serde(default)]
+    pub a: u64,
+    #[serde(default)]
+    pub c: u64,
+    #[serde(default)]
+    pub g: u64,
+    #[serde(default)]
+    pub u: u64,
+    #[serde(default)]
+    pub t: u64,
+    /// Nucleotides with any other letter in the alphabet. Each IUPAC
+    /// ambiguity code also has its own column below, so this is their sum
+    /// plus any letters of a custom alphabet which are not IUPAC codes.
+    #[serde(default)]
+    pub ambiguous: u64,
+    /// The number of nucleotides with each IUPAC ambiguity code, and X.
+    #[serde(default)]
+    pub r: u64,
+    #[serde(default)]
+    pub y: u64,
+    #[serde(default)]
+    pub s: u64,
+    #[serde(default)]
+    pub w: u64,
+    #[serde(default)]
+    pub k: u64,
+    #[serde(default)]
+    pub m: u64,
+    #[serde(default)]
+    pub b: u64,
+    #[serde(default)]
+    pub d: u64,
+    #[serde(default)]
+    pub h: u64,
+    #[serde(default)]
+    pub v: u64,
+    #[serde(default)]
+    pub n: u64,
+    #[serde(default)]
+    pub x: u64,
+    /// `<text>` elements which were not counted, as they have no class or
+    /// are not a letter in the alphabet. This includes labels.
+    #[serde(default)]
+    pub skipped: u64,
+}
+
+/// A diagram which could not be parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ParseFailure {
+    pub urs: Option<UrsId>,
+    pub path: Option<PathBuf>,
+    /// The byte offset in the SVG the error was found at, if known.
+    pub offset: Option<usize>,
+    pub reason: String,
+}
+
+/// How to count the colors in a set of diagrams.
+pub struct CountOptions {
+    pub alphabet: Alphabet,
+    /// A CSV file to record diagrams which cannot be parsed in, instead of
+    /// stopping at the first one.
+    pub keep_going: Option<PathBuf>,
+}
+
+/// The letters which are counted as nucleotides, in either case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alphabet {
+    letters: Vec<char>,
+}
+
+/// The IUPAC nucleotide codes, and X which R2DT uses for unknown bases.
+pub const IUPAC: &str = "ACGUTRYSWKMBDHVNX";
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Counts {
-    urs: String,
-    changed: u64,
-    unchanged: u64,
-    inserted: u64,
-    moved: u64,
-    rotated: u64,
-    total: u64,
+/// A single colored nucleotide in a diagram.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Nucleotide {
+    pub urs: UrsId,
+    /// The position of the nucleotide in the diagram, starting at 1.
+    pub index: u64,
+    pub letter: String,
+    pub class: String,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
 }
 
-fn is_valid_letter(text: String) -> bool {
-    match text.as_ref() {
-        "A" | "C" | "G" | "U" | "X" => true,
-        _ => false,
+impl ParseFailure {
+    /// Describe `error`, filling in any of the URS and path it does not
+    /// already contain.
+    fn from_error(urs: Option<UrsId>, path: Option<&Path>, error: anyhow::Error) -> Self {
+        let failure = match error.downcast::<Self>() {
+            Ok(failure) => failure,
+            Err(error) => Self {
+                urs: None,
+                path: None,
+                offset: None,
+                reason: error.to_string(),
+            },
+        };
+        return Self {
+            urs: failure.urs.or(urs),
+            path: failure.path.or_else(|| path.map(PathBuf::from)),
+            ..failure
+        };
     }
 }
 
-fn count_reader<B: BufRead>(urs: String, reader: &mut Reader<B>) -> Result<Counts> {
-    let mut counts = Counts {
-        urs,
-        changed: 0,
-        unchanged: 0,
-        inserted: 0,
-        moved: 0,
-        rotated: 0,
-        total: 0,
-    };
+impl fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not parse")?;
+        if let Some(urs) = &self.urs {
+            write!(f, " {}", urs)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " {:?}", path)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        return write!(f, ": {}", self.reason);
+    }
+}
+
+impl std::error::Error for ParseFailure {}
+
+/// An error from the XML parser at the current position of `reader`.
+fn xml_failure<B: BufRead>(
+    urs: UrsId,
+    reader: &Reader<B>,
+    error: quick_xml::Error,
+) -> anyhow::Error {
+    return anyhow::Error::new(ParseFailure {
+        urs: Some(urs),
+        path: None,
+        offset: Some(reader.buffer_position()),
+        reason: error.to_string(),
+    });
+}
+
+impl Default for Alphabet {
+    fn default() -> Self {
+        return Self {
+            letters: IUPAC.chars().collect(),
+        };
+    }
+}
+
+impl Alphabet {
+    /// Check if `text` is a single letter of the alphabet.
+    pub fn contains(&self, text: &str) -> bool {
+        let mut chars = text.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => self.letters.contains(&c.to_ascii_uppercase()),
+            _ => false,
+        };
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = anyhow::Error;
+
+    /// Parse `iupac`, or the letters to accept such as `ACGU`.
+    fn from_str(raw: &str) -> Result<Self> {
+        if raw == "iupac" {
+            return Ok(Self::default());
+        }
+        if raw.is_empty() || !raw.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(anyhow!(
+                "Unknown alphabet '{}', expected iupac or a list of letters",
+                raw
+            ));
+        }
+        return Ok(Self {
+            letters: raw.chars().map(|c| c.to_ascii_uppercase()).collect(),
+        });
+    }
+}
+
+/// Call `handle` with every colored nucleotide in an SVG read from `reader`,
+/// in the order they are drawn. Returns the number of other `<text>`
+/// elements.
+fn each_nucleotide<B, F>(
+    urs: UrsId,
+    reader: &mut Reader<B>,
+    alphabet: &Alphabet,
+    mut handle: F,
+) -> Result<u64>
+where
+    B: BufRead,
+    F: FnMut(Nucleotide),
+{
+    let mut index = 0;
+    let mut skipped = 0;
     let mut buf = Vec::new();
     loop {
         match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) => match e.name() {
-                b"text" => {
-                    let text = reader.read_text(e.name(), &mut Vec::new())?;
-                    let class = e.attributes().with_checks(false).find(|attr| match attr {
-                        Ok(a) => a.key == b"class",
-                        _ => false,
-                    });
-                    match (is_valid_letter(text), class) {
-                        (true, Some(Ok(v))) => match v.value.as_ref() {
-                            b"green" => counts.changed += 1,
-                            b"black" => counts.unchanged += 1,
-                            b"red" => counts.inserted += 1,
-                            b"blue" => counts.moved += 1,
-                            b"brown" => counts.rotated += 1,
-                            _ => (),
-                        },
+            Ok(Event::Start(ref e)) if e.name() == b"text" => {
+                let (mut class, mut x, mut y) = (None, None, None);
+                for attr in e.attributes().with_checks(false).filter_map(|a| a.ok()) {
+                    let value = String::from_utf8_lossy(&attr.value).into_owned();
+                    match attr.key {
+                        b"class" => class = Some(value),
+                        b"x" => x = value.trim().parse().ok(),
+                        b"y" => y = value.trim().parse().ok(),
                         _ => (),
                     }
                 }
-                _ => (),
-            },
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+                let letter = reader
+                    .read_text(e.name(), &mut Vec::new())
+                    .map_err(|e| xml_failure(urs, reader, e))?;
+                match (alphabet.contains(&letter), class) {
+                    (true, Some(class)) => {
+                        index += 1;
+                        handle(Nucleotide {
+                            urs,
+                            index,
+                            letter,
+                            class,
+                            x,
+                            y,
+                        });
+                    }
+                    _ => skipped += 1,
+                }
+            }
+            Err(e) => return Err(xml_failure(urs, reader, e)),
             Ok(Event::Eof) => break,
             _ => (),
         }
+        buf.clear();
     }
-    counts.total =
-        counts.changed + counts.unchanged + counts.inserted + counts.moved + counts.rotated;
+    return Ok(skipped);
+}
+
+/// Count the colors and letters of all nucleotides in an SVG read from
+/// `reader`.
+pub fn count_reader<B: BufRead>(
+    urs: UrsId,
+    reader: &mut Reader<B>,
+    alphabet: &Alphabet,
+) -> Result<Counts> {
+    let mut counts = Counts {
+        urs,
+        ..Default::default()
+    };
+    let mut other: BTreeMap<String, u64> = BTreeMap::new();
+    // Letters of a custom alphabet which are not IUPAC codes are only
+    // counted as ambiguous.
+    let mut unlisted = 0;
+    counts.skipped = each_nucleotide(urs, reader, alphabet, |nucleotide| {
+        let letter = nucleotide.letter.to_ascii_uppercase();
+        let code = match letter.as_ref() {
+            "A" => &mut counts.a,
+            "C" => &mut counts.c,
+            "G" => &mut counts.g,
+            "U" => &mut counts.u,
+            "T" => &mut counts.t,
+            _ => {
+                counts.ambiguous += 1;
+                match letter.as_ref() {
+                    "R" => &mut counts.r,
+                    "Y" => &mut counts.y,
+                    "S" => &mut counts.s,
+                    "W" => &mut counts.w,
+                    "K" => &mut counts.k,
+                    "M" => &mut counts.m,
+                    "B" => &mut counts.b,
+                    "D" => &mut counts.d,
+                    "H" => &mut counts.h,
+                    "V" => &mut counts.v,
+                    "N" => &mut counts.n,
+                    "X" => &mut counts.x,
+                    _ => &mut unlisted,
+                }
+            }
+        };
+        *code += 1;
+        match nucleotide.class.as_ref() {
+            "green" => counts.changed += 1,
+            "black" => counts.unchanged += 1,
+            "red" => counts.inserted += 1,
+            "blue" => counts.moved += 1,
+            "brown" => counts.rotated += 1,
+            _ => *other.entry(nucleotide.class).or_insert(0) += 1,
+        }
+    })?;
+    counts.other = other.values().sum();
+    counts.other_classes = other
+        .iter()
+        .map(|(class, count)| format!("{}:{}", class, count))
+        .collect::<Vec<String>>()
+        .join(";");
+    counts.total = counts.changed
+        + counts.unchanged
+        + counts.inserted
+        + counts.moved
+        + counts.rotated
+        + counts.other;
     return Ok(counts);
 }
 
-fn count_path(path: &PathBuf) -> Result<Counts> {
-    let urs = match urs_utils::filename_urs(&path) {
-        Some(u) => Ok(u),
-        None => Err(anyhow!("SVG does not have a URS")),
-    }?;
+/// All colored nucleotides in an SVG read from `reader`.
+pub fn nucleotide_reader<B: BufRead>(
+    urs: UrsId,
+    reader: &mut Reader<B>,
+    alphabet: &Alphabet,
+) -> Result<Vec<Nucleotide>> {
+    let mut nucleotides = Vec::new();
+    each_nucleotide(urs, reader, alphabet, |n| nucleotides.push(n))?;
+    return Ok(nucleotides);
+}
+
+/// What a structural check of an SVG found.
+#[derive(Debug, PartialEq)]
+pub struct SvgCheck {
+    /// The number of colored nucleotide `<text>` elements.
+    pub nucleotides: u64,
+    /// The first URS found in any text or attribute.
+    pub identifier: Option<UrsId>,
+}
+
+fn find_urs(text: &[u8]) -> Option<UrsId> {
+    lazy_static! {
+        static ref PATTERN: Regex = Regex::new(r"URS[0-9A-F]{10}(_\d+)?").unwrap();
+    }
+    let text = String::from_utf8_lossy(text);
+    return PATTERN.find(&text).and_then(|m| m.as_str().parse().ok());
+}
+
+fn attribute_urs(element: &BytesStart) -> Result<Option<UrsId>> {
+    for attr in element.attributes().with_checks(false) {
+        if let Some(urs) = find_urs(&attr?.value) {
+            return Ok(Some(urs));
+        }
+    }
+    return Ok(None);
+}
+
+/// Parse an SVG read from `reader` completely, failing if it is not well
+/// formed XML with an `<svg>` element.
+pub fn check_reader<B: BufRead>(reader: &mut Reader<B>) -> Result<SvgCheck> {
+    let mut check = SvgCheck {
+        nucleotides: 0,
+        identifier: None,
+    };
+    let mut seen_svg = false;
+    let mut depth: usize = 0;
+    let mut buf = Vec::new();
+    loop {
+        let mut found = None;
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) if e.name() == b"text" => {
+                found = attribute_urs(e)?;
+                let has_class = e
+                    .attributes()
+                    .with_checks(false)
+                    .any(|a| a.is_ok_and(|a| a.key == b"class"));
+                let text = reader.read_text(e.name(), &mut Vec::new())?;
+                if has_class && Alphabet::default().contains(&text) {
+                    check.nucleotides += 1;
+                } else if found.is_none() {
+                    found = find_urs(text.as_bytes());
+                }
+            }
+            Ok(Event::Start(ref e)) => {
+                depth += 1;
+                seen_svg |= e.name() == b"svg";
+                found = attribute_urs(e)?;
+            }
+            Ok(Event::Empty(ref e)) => {
+                seen_svg |= e.name() == b"svg";
+                found = attribute_urs(e)?;
+            }
+            Ok(Event::Text(ref e)) => found = find_urs(&e.unescaped()?),
+            Ok(Event::End(_)) => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| anyhow!("Unmatched end tag"))?;
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Error at position {}: {:?}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+            _ => (),
+        }
+        if check.identifier.is_none() {
+            check.identifier = found;
+        }
+        buf.clear();
+    }
+    if depth != 0 {
+        return Err(anyhow!("{} elements are not closed", depth));
+    }
+    if !seen_svg {
+        return Err(anyhow!("No <svg> element"));
+    }
+    return Ok(check);
+}
+
+type SvgReader = Reader<Box<dyn BufRead>>;
+
+/// Parse an SVG file, which may be gzip compressed, with `parse`. The URS is
+/// taken from the filename. All errors are a `ParseFailure`.
+fn parse_path<T, F>(path: &PathBuf, parse: F) -> Result<T>
+where
+    F: FnOnce(UrsId, &mut SvgReader) -> Result<T>,
+{
+    let urs = urs_utils::filename_urs(path);
+    return open_path(path, urs, parse)
+        .map_err(|e| anyhow::Error::new(ParseFailure::from_error(urs, Some(path), e)));
+}
 
+fn open_path<T, F>(path: &PathBuf, urs: Option<UrsId>, parse: F) -> Result<T>
+where
+    F: FnOnce(UrsId, &mut SvgReader) -> Result<T>,
+{
+    let urs = urs.ok_or_else(|| anyhow!("SVG does not have a URS"))?;
     info!("Parsing data for {:?}", path);
-    return match path.extension() {
-        Some(ext) => match ext.to_str() {
-            Some("svg") => {
-                trace!("Parsing as svg");
-                let mut reader = Reader::from_file(path)?;
-                return count_reader(urs, &mut reader);
-            }
-            Some("gz") => {
-                trace!("Parsing as compressed svg");
-                let file = File::open(path)?;
-                let decoder = GzDecoder::new(file);
-                let buf = BufReader::new(decoder);
-                let mut reader = Reader::from_reader(buf);
-                return count_reader(urs, &mut reader);
-            }
-            e => Err(anyhow!("Cannot parse file with {:?} extension", e)),
-        },
-        None => Err(anyhow!("File {:?} does not have an extension", path)),
+    let file = BufReader::new(File::open(path)?);
+    let buf: Box<dyn BufRead> = match path.extension().and_then(|e| e.to_str()) {
+        Some("svg") => {
+            trace!("Parsing as svg");
+            Box::new(file)
+        }
+        Some("gz") => {
+            trace!("Parsing as compressed svg");
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        }
+        Some(e) => return Err(anyhow!("Cannot parse file with {:?} extension", e)),
+        None => return Err(anyhow!("File {:?} does not have an extension", path)),
     };
+    return parse(urs, &mut Reader::from_reader(buf));
 }
 
-pub fn count_tree(path: PathBuf) -> Result<()> {
-    let walker = WalkDir::new(path);
+/// Count the colors in an SVG file, which may be gzip compressed. The URS is
+/// taken from the filename.
+pub fn count_path(path: &PathBuf, alphabet: &Alphabet) -> Result<Counts> {
+    return parse_path(path, |urs, reader| count_reader(urs, reader, alphabet));
+}
+
+/// All colored nucleotides in an SVG file, which may be gzip compressed.
+pub fn nucleotide_path(path: &PathBuf, alphabet: &Alphabet) -> Result<Vec<Nucleotide>> {
+    return parse_path(path, |urs, reader| nucleotide_reader(urs, reader, alphabet));
+}
+
+/// All SVG files in a tree, in a stable order.
+pub fn svg_paths(path: PathBuf) -> Result<impl Iterator<Item = PathBuf>> {
     let mut builder = GlobSetBuilder::new();
     builder.add(Glob::new("*.svg")?);
     builder.add(Glob::new("*.svg.gz")?);
     let glob = builder.build()?;
 
-    let counts = walker
+    let paths = WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
         .into_iter()
         .filter_map(Result::ok)
         .filter(move |e| glob.is_match(e.file_name()))
-        .map(|path| count_path(&path.into_path()));
+        .map(|e| e.into_path());
+    return Ok(paths);
+}
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    for count in counts {
-        let c = count?;
-        wtr.serialize(c)?;
+struct Throughput {
+    start: Instant,
+    last: Instant,
+    total: usize,
+}
+
+impl Throughput {
+    fn new() -> Self {
+        let now = Instant::now();
+        return Self {
+            start: now,
+            last: now,
+            total: 0,
+        };
+    }
+
+    fn record(&mut self) {
+        self.total += 1;
+        if self.last.elapsed() >= Duration::from_secs(10) {
+            self.last = Instant::now();
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        info!(
+            "Counted {} files in {:.1} seconds ({:.1} files/second)",
+            self.total,
+            seconds,
+            self.total as f64 / seconds.max(f64::EPSILON)
+        );
+    }
+}
+
+/// How many files each parsing thread may be given before the result of the
+/// earliest one has been produced.
+const IN_FLIGHT_PER_JOB: usize = 4;
+
+/// An iterator over the counts, or other results, of all SVGs in a tree,
+/// which are parsed by a pool of threads. Results are produced in the order
+/// the files are found, regardless of which thread parsed them. Only a few
+/// files per thread are handed out ahead of the next result, so results
+/// waiting for an earlier one stay bounded.
+pub struct TreeCounts<T = Counts> {
+    counts: mpsc::Receiver<(usize, Result<T>)>,
+    slots: mpsc::Receiver<()>,
+    pending: BTreeMap<usize, Result<T>>,
+    next_index: usize,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<T> Iterator for TreeCounts<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(count) = self.pending.remove(&self.next_index) {
+                self.next_index += 1;
+                let _ = self.slots.try_recv();
+                return Some(count);
+            }
+            match self.counts.recv() {
+                Ok((index, count)) => {
+                    self.pending.insert(index, count);
+                }
+                Err(_) => {
+                    let failed = self.threads.drain(..).map(|t| t.join()).any(|r| r.is_err());
+                    return match failed {
+                        true => Some(Err(anyhow!("Counting thread failed"))),
+                        false => None,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Count all SVGs in the tree using `jobs` parsing threads.
+pub fn tree_counts(path: PathBuf, jobs: usize, alphabet: Alphabet) -> Result<TreeCounts> {
+    return tree_map(path, jobs, move |p| count_path(p, &alphabet));
+}
+
+/// Parse all SVGs in the tree with `parse`, using `jobs` parsing threads.
+pub fn tree_map<T, F>(path: PathBuf, jobs: usize, parse: F) -> Result<TreeCounts<T>>
+where
+    T: Send + 'static,
+    F: Fn(&PathBuf) -> Result<T> + Send + Sync + 'static,
+{
+    let jobs = jobs.max(1);
+    let parse = Arc::new(parse);
+    let paths = svg_paths(path)?;
+    let window = jobs * IN_FLIGHT_PER_JOB;
+    let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(window);
+    let (path_tx, path_rx) = mpsc::sync_channel::<(usize, PathBuf)>(window);
+    let (count_tx, count_rx) = mpsc::sync_channel::<(usize, Result<T>)>(window);
+    let path_rx = Arc::new(Mutex::new(path_rx));
+
+    let mut threads = Vec::new();
+    threads.push(thread::spawn(move || {
+        for (index, path) in paths.enumerate() {
+            if slot_tx.send(()).is_err() || path_tx.send((index, path)).is_err() {
+                break;
+            }
+        }
+    }));
+
+    for _ in 0..jobs {
+        let path_rx = Arc::clone(&path_rx);
+        let count_tx = count_tx.clone();
+        let parse = Arc::clone(&parse);
+        threads.push(thread::spawn(move || loop {
+            let next = path_rx.lock().unwrap().recv();
+            match next {
+                Ok((index, path)) => {
+                    // A missing result would stall every later one.
+                    let count = panic::catch_unwind(AssertUnwindSafe(|| parse(&path)))
+                        .unwrap_or_else(|_| Err(anyhow!("Counting thread failed on {:?}", path)));
+                    if count_tx.send((index, count)).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }));
     }
-    return Ok(());
+
+    return Ok(TreeCounts {
+        counts: count_rx,
+        slots: slot_rx,
+        pending: BTreeMap::new(),
+        next_index: 0,
+        threads,
+    });
 }
 
-pub fn count_json(filename: PathBuf) -> Result<()> {
+/// Parse all diagrams in a file of JSON encoded diagrams, one per line. All
+/// errors are a `ParseFailure`.
+fn json_map<T, F>(filename: PathBuf, parse: F) -> Result<impl Iterator<Item = Result<T>>>
+where
+    F: Fn(UrsId, &mut SvgReader) -> Result<T>,
+{
     let file = File::open(filename)?;
     let file = BufReader::new(file);
-    let counts = file.lines().into_iter().map(|line| {
-        let line = line?.replace("\\\\", "\\");
-        let entry: JsonDiagram = serde_json::from_str(&line)?;
-        let mut reader = Reader::from_str(entry.svg.as_ref());
-        return count_reader(entry.urs, &mut reader);
+    let results = file.lines().map(move |line| {
+        let entry: JsonDiagram = line
+            .map_err(anyhow::Error::from)
+            .and_then(|l| Ok(serde_json::from_str(&l.replace("\\\\", "\\"))?))
+            .map_err(|e| anyhow::Error::new(ParseFailure::from_error(None, None, e)))?;
+        let urs = entry.urs;
+        let svg: Box<dyn BufRead> = Box::new(io::Cursor::new(entry.svg.into_bytes()));
+        return parse(urs, &mut Reader::from_reader(svg))
+            .map_err(|e| anyhow::Error::new(ParseFailure::from_error(Some(urs), None, e)));
     });
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    for count in counts {
-        let c = count?;
-        wtr.serialize(c)?;
+    return Ok(results);
+}
+
+/// The counts of all diagrams in a file of JSON encoded diagrams, one per
+/// line.
+pub fn json_counts(
+    filename: PathBuf,
+    alphabet: Alphabet,
+) -> Result<impl Iterator<Item = Result<Counts>>> {
+    return json_map(filename, move |urs, reader| {
+        count_reader(urs, reader, &alphabet)
+    });
+}
+
+/// Records diagrams which could not be parsed, or stops at the first one if
+/// there is no file to record them in.
+pub struct Failures {
+    writer: Option<csv::Writer<File>>,
+    total: usize,
+}
+
+impl Failures {
+    pub fn new(path: &Option<PathBuf>) -> Result<Self> {
+        let writer = match path {
+            Some(path) => Some(csv::Writer::from_path(path)?),
+            None => None,
+        };
+        return Ok(Self { writer, total: 0 });
+    }
+
+    /// Record a diagram which could not be parsed. Any other error, such as
+    /// a counting thread failing, is always returned.
+    pub fn record(&mut self, error: anyhow::Error) -> Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Err(error),
+        };
+        let failure = error.downcast::<ParseFailure>()?;
+        warn!("{}", failure);
+        writer.serialize(&failure)?;
+        self.total += 1;
+        return Ok(());
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Some(mut writer) = self.writer {
+            writer.flush()?;
+        }
+        if self.total > 0 {
+            warn!("Could not parse {} diagrams", self.total);
+        }
+        return Ok(());
+    }
+}
+
+/// Write all rows produced from each diagram as CSV.
+fn write_csv<I, T, R, W>(results: I, keep_going: &Option<PathBuf>, out: W) -> Result<()>
+where
+    I: Iterator<Item = Result<T>>,
+    T: IntoIterator<Item = R>,
+    R: Serialize,
+    W: Write,
+{
+    let mut wtr = csv::Writer::from_writer(out);
+    let mut failures = Failures::new(keep_going)?;
+    let mut throughput = Throughput::new();
+    for result in results {
+        match result {
+            Ok(rows) => {
+                for row in rows {
+                    wtr.serialize(row)?;
+                }
+            }
+            Err(error) => failures.record(error)?,
+        }
+        throughput.record();
+    }
+    wtr.flush()?;
+    throughput.report();
+    return failures.finish();
+}
+
+/// Write the counts of every SVG in the tree to `out` as CSV.
+pub fn count_tree<W: Write>(
+    path: PathBuf,
+    jobs: usize,
+    options: CountOptions,
+    out: W,
+) -> Result<()> {
+    let counts = tree_counts(path, jobs, options.alphabet)?;
+    return write_csv(counts.map(|c| c.map(iter::once)), &options.keep_going, out);
+}
+
+/// Write the counts of every diagram in a JSON file to `out` as CSV.
+pub fn count_json<W: Write>(filename: PathBuf, options: CountOptions, out: W) -> Result<()> {
+    let counts = json_counts(filename, options.alphabet)?;
+    return write_csv(counts.map(|c| c.map(iter::once)), &options.keep_going, out);
+}
+
+/// Write every colored nucleotide of every SVG in the tree.
+pub fn nucleotides_tree<W: Write>(
+    path: PathBuf,
+    jobs: usize,
+    options: CountOptions,
+    out: W,
+) -> Result<()> {
+    let alphabet = options.alphabet;
+    let nucleotides = tree_map(path, jobs, move |p| nucleotide_path(p, &alphabet))?;
+    return write_csv(nucleotides, &options.keep_going, out);
+}
+
+/// Write every colored nucleotide of every diagram in a JSON file.
+pub fn nucleotides_json<W: Write>(filename: PathBuf, options: CountOptions, out: W) -> Result<()> {
+    let alphabet = options.alphabet;
+    let nucleotides = json_map(filename, move |urs, reader| {
+        nucleotide_reader(urs, reader, &alphabet)
+    })?;
+    return write_csv(nucleotides, &options.keep_going, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn reads_counts_without_new_columns() -> Result<()> {
+        let csv = "urs,changed,unchanged,inserted,moved,rotated,total\n\
+                   URS0000000372,1,2,3,4,5,15\n";
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let counts: Vec<Counts> = reader.deserialize().collect::<csv::Result<_>>()?;
+        assert_eq!(
+            counts,
+            vec![Counts {
+                urs: "URS0000000372".parse()?,
+                changed: 1,
+                unchanged: 2,
+                inserted: 3,
+                moved: 4,
+                rotated: 5,
+                total: 15,
+                ..Default::default()
+            }]
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn counts_other_classes() -> Result<()> {
+        let svg = r#"<svg>
+            <text class="green" x="1.5" y="2">A</text>
+            <text class="purple" x="3" y="4">C</text>
+            <text class="purple">G</text>
+            <text class="orange">U</text>
+            <text>URS0000000372</text>
+        </svg>"#;
+        let urs: UrsId = "URS0000000372".parse()?;
+        let alphabet = Alphabet::default();
+        let counts = count_reader(urs, &mut Reader::from_str(svg), &alphabet)?;
+        assert_eq!(counts.changed, 1);
+        assert_eq!(counts.other, 3);
+        assert_eq!(counts.other_classes, "orange:1;purple:2");
+        assert_eq!(counts.total, 4);
+
+        let nucleotides = nucleotide_reader(urs, &mut Reader::from_str(svg), &alphabet)?;
+        assert_eq!(nucleotides.len(), 4);
+        assert_eq!(
+            nucleotides[1],
+            Nucleotide {
+                urs,
+                index: 2,
+                letter: String::from("C"),
+                class: String::from("purple"),
+                x: Some(3.0),
+                y: Some(4.0),
+            }
+        );
+        assert_eq!(nucleotides[2].x, None);
+        return Ok(());
+    }
+
+    #[test]
+    fn counts_letters_in_alphabet() -> Result<()> {
+        let svg = r#"<svg>
+            <text class="black">a</text>
+            <text class="black">T</text>
+            <text class="green">N</text>
+            <text class="green">y</text>
+            <text class="red">Z</text>
+            <text>G</text>
+            <text class="black">10</text>
+        </svg>"#;
+        let urs: UrsId = "URS0000000372".parse()?;
+        let counts = count_reader(urs, &mut Reader::from_str(svg), &Alphabet::default())?;
+        assert_eq!((counts.a, counts.t, counts.ambiguous), (1, 1, 2));
+        assert_eq!((counts.n, counts.y, counts.r), (1, 1, 0));
+        assert_eq!((counts.total, counts.skipped), (4, 3));
+
+        let strict: Alphabet = "ACGU".parse()?;
+        let counts = count_reader(urs, &mut Reader::from_str(svg), &strict)?;
+        assert_eq!((counts.total, counts.skipped), (1, 6));
+        assert!("AC-GU".parse::<Alphabet>().is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() -> Result<()> {
+        let urs: UrsId = "URS0000000372".parse()?;
+        let svg = r#"<svg><text class="black">A</text></g></svg>"#;
+        let error = count_reader(urs, &mut Reader::from_str(svg), &Alphabet::default())
+            .expect_err("Should not parse");
+        let failure = ParseFailure::from_error(None, Some(Path::new("a.svg")), error);
+        assert_eq!(failure.urs, Some(urs));
+        assert_eq!(failure.path, Some(PathBuf::from("a.svg")));
+        assert_eq!(failure.offset, Some(35));
+        assert!(failure
+            .to_string()
+            .starts_with("Could not parse URS0000000372 \"a.svg\" at byte 35"));
+        return Ok(());
+    }
+
+    /// Write `count` small diagrams spread over a few directories.
+    fn write_tree(dir: &TempDir, count: usize) -> Result<PathBuf> {
+        for i in 0..count {
+            let svg = format!(
+                r#"<svg><text class="green">{}</text></svg>"#,
+                "A".repeat(i + 1)
+            );
+            dir.write(&format!("tree/{}/URS{:010X}.svg", i % 3, i), &svg)?;
+        }
+        return Ok(dir.path().join("tree"));
+    }
+
+    #[test]
+    fn counts_tree_in_walk_order() -> Result<()> {
+        let dir = TempDir::new("tree-order")?;
+        let tree = write_tree(&dir, 12)?;
+        let alphabet = Alphabet::default();
+        let serial = tree_counts(tree.clone(), 1, alphabet.clone())?.collect::<Result<Vec<_>>>()?;
+        let parallel = tree_counts(tree.clone(), 4, alphabet)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(serial.len(), 12);
+        assert_eq!(parallel, serial);
+
+        // Later files finish first, so results arrive out of order.
+        let paths: Vec<PathBuf> = svg_paths(tree.clone())?.collect();
+        let found = tree_map(tree, 4, |path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let i = usize::from_str_radix(&name[3..13], 16)?;
+            thread::sleep(Duration::from_millis((12 - i as u64) * 5));
+            return Ok(path.clone());
+        })?;
+        assert_eq!(found.collect::<Result<Vec<_>>>()?, paths);
+        return Ok(());
+    }
+
+    #[test]
+    fn reports_errors_from_parsing_threads() -> Result<()> {
+        let dir = TempDir::new("tree-errors")?;
+        let tree = write_tree(&dir, 6)?;
+        let paths: Vec<PathBuf> = svg_paths(tree.clone())?.collect();
+        let failing = paths[1].clone();
+        let panicking = paths[4].clone();
+        let found: Vec<Result<PathBuf>> = tree_map(tree, 3, move |path| {
+            if *path == failing {
+                return Err(anyhow!("Bad diagram"));
+            }
+            if *path == panicking {
+                panic!("Parser crashed");
+            }
+            return Ok(path.clone());
+        })?
+        .collect();
+        assert_eq!(found.len(), 6);
+        for (i, result) in found.iter().enumerate() {
+            match (i, result) {
+                (1, Err(e)) => assert_eq!(e.to_string(), "Bad diagram"),
+                (4, Err(e)) => assert!(e.to_string().starts_with("Counting thread failed")),
+                (_, Ok(path)) => assert_eq!(path, &paths[i]),
+                (_, result) => panic!("Unexpected result {:?}", result),
+            }
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn bounds_files_parsed_ahead() -> Result<()> {
+        let dir = TempDir::new("tree-window")?;
+        let tree = write_tree(&dir, 60)?;
+        let first = svg_paths(tree.clone())?.next().unwrap();
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&started);
+        let mut found = tree_map(tree, 2, move |path| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if *path == first {
+                thread::sleep(Duration::from_millis(200));
+            }
+            return Ok(());
+        })?;
+        assert!(found.next().is_some());
+        assert!(started.load(Ordering::SeqCst) <= 2 * IN_FLIGHT_PER_JOB + 1);
+        assert_eq!(found.count(), 59);
+        return Ok(());
     }
-    return Ok(());
 }
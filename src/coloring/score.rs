@@ -0,0 +1,265 @@
+//! Grade diagrams using metrics derived from their color counts, to decide if
+//! they are good enough to publish.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::coloring::Counts;
+use crate::urs::UrsId;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Grade {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Limits on the metrics of a diagram, any which are not set are not checked.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_changed: Option<f64>,
+    pub max_inserted: Option<f64>,
+    /// The limit for moved and rotated nucleotides combined.
+    pub max_moved: Option<f64>,
+    /// The smallest fraction of the sequence which must be drawn.
+    pub min_length_ratio: Option<f64>,
+}
+
+/// The limits a diagram must be within to pass, or to only warn. This can be
+/// loaded from a JSON file like `{"fail": {"max_inserted": 0.5}}`, where any
+/// missing section uses the default limits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub warn: Limits,
+    pub fail: Limits,
+}
+
+/// The length of a sequence, as loaded from a CSV file.
+#[derive(Deserialize, Debug)]
+pub struct SequenceLength {
+    pub urs: UrsId,
+    pub length: u64,
+}
+
+/// The metrics and grade of a single diagram.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Score {
+    pub urs: UrsId,
+    pub total: u64,
+    pub sequence_length: Option<u64>,
+    pub fraction_changed: f64,
+    pub fraction_inserted: f64,
+    pub fraction_moved: f64,
+    /// The number of nucleotides drawn compared to the sequence length.
+    pub length_ratio: Option<f64>,
+    pub grade: Grade,
+    /// Every limit the diagram is outside of, separated by `;`.
+    pub reasons: String,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        return Self {
+            warn: Limits {
+                max_changed: Some(0.3),
+                max_inserted: Some(0.1),
+                max_moved: Some(0.1),
+                min_length_ratio: Some(0.95),
+            },
+            fail: Limits {
+                max_changed: Some(0.5),
+                max_inserted: Some(0.25),
+                max_moved: Some(0.25),
+                min_length_ratio: Some(0.8),
+            },
+        };
+    }
+}
+
+impl Policy {
+    pub fn from_path(path: &PathBuf) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        return Ok(serde_json::from_reader(reader)?);
+    }
+}
+
+impl Limits {
+    /// Descriptions of every limit `score` is outside of.
+    fn violations(&self, score: &Score) -> Vec<String> {
+        let mut found = Vec::new();
+        let maximums = [
+            ("changed", score.fraction_changed, self.max_changed),
+            ("inserted", score.fraction_inserted, self.max_inserted),
+            ("moved", score.fraction_moved, self.max_moved),
+        ];
+        for (name, value, limit) in maximums.iter() {
+            if let Some(limit) = limit {
+                if value > limit {
+                    found.push(format!("{} {:.3} > {}", name, value, limit));
+                }
+            }
+        }
+        if let (Some(ratio), Some(limit)) = (score.length_ratio, self.min_length_ratio) {
+            if ratio < limit {
+                found.push(format!("length ratio {:.3} < {}", ratio, limit));
+            }
+        }
+        return found;
+    }
+}
+
+fn fraction(count: u64, total: u64) -> f64 {
+    return match total {
+        0 => 0.0,
+        _ => count as f64 / total as f64,
+    };
+}
+
+/// Compute the metrics of a diagram and grade them with `policy`. Diagrams
+/// without any nucleotides always fail.
+pub fn score(counts: &Counts, sequence_length: Option<u64>, policy: &Policy) -> Score {
+    let total = counts.total;
+    let mut score = Score {
+        urs: counts.urs,
+        total,
+        sequence_length,
+        fraction_changed: fraction(counts.changed, total),
+        fraction_inserted: fraction(counts.inserted, total),
+        fraction_moved: fraction(counts.moved + counts.rotated, total),
+        length_ratio: sequence_length
+            .filter(|l| *l > 0)
+            .map(|l| total as f64 / l as f64),
+        grade: Grade::Pass,
+        reasons: String::new(),
+    };
+
+    let mut reasons = Vec::new();
+    if total == 0 {
+        score.grade = Grade::Fail;
+        reasons.push(String::from("no nucleotides"));
+    }
+    for (grade, limits) in &[(Grade::Fail, &policy.fail), (Grade::Warn, &policy.warn)] {
+        let found = limits.violations(&score);
+        if !found.is_empty() {
+            score.grade = score.grade.max(*grade);
+            reasons.extend(found);
+            break;
+        }
+    }
+    score.reasons = reasons.join(";");
+    return score;
+}
+
+fn load_lengths(path: &PathBuf) -> Result<HashMap<UrsId, u64>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut lengths = HashMap::new();
+    for record in reader.deserialize() {
+        let record: SequenceLength = record?;
+        lengths.insert(record.urs, record.length);
+    }
+    return Ok(lengths);
+}
+
+/// Score every diagram in a CSV of color counts, as produced by `coloring
+/// tree`, and write them to `out` as CSV. Sequence lengths are loaded from a
+/// CSV with `urs` and `length` columns, diagrams without a length are not
+/// checked against it.
+pub fn write_scores<W: Write>(
+    counts_file: PathBuf,
+    lengths_file: Option<PathBuf>,
+    policy_file: Option<PathBuf>,
+    out: W,
+) -> Result<()> {
+    let policy = match policy_file {
+        Some(path) => Policy::from_path(&path)?,
+        None => Policy::default(),
+    };
+    let lengths = match lengths_file {
+        Some(path) => load_lengths(&path)?,
+        None => HashMap::new(),
+    };
+
+    let mut reader = csv::Reader::from_path(counts_file)?;
+    let mut writer = csv::Writer::from_writer(out);
+    let mut grades: HashMap<Grade, usize> = HashMap::new();
+    for counts in reader.deserialize() {
+        let counts: Counts = counts?;
+        let length = lengths.get(&counts.urs).cloned();
+        let score = score(&counts, length, &policy);
+        *grades.entry(score.grade).or_insert(0) += 1;
+        writer.serialize(score)?;
+    }
+    writer.flush()?;
+
+    log::info!(
+        "Graded {} pass, {} warn and {} fail",
+        grades.get(&Grade::Pass).unwrap_or(&0),
+        grades.get(&Grade::Warn).unwrap_or(&0),
+        grades.get(&Grade::Fail).unwrap_or(&0)
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(changed: u64, inserted: u64, moved: u64, total: u64) -> Counts {
+        return Counts {
+            urs: "URS0000000372".parse().unwrap(),
+            changed,
+            inserted,
+            moved,
+            unchanged: total - changed - inserted - moved,
+            total,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn grades_with_policy() -> Result<()> {
+        let policy = Policy::default();
+        assert_eq!(
+            score(&counts(10, 0, 0, 100), Some(100), &policy).grade,
+            Grade::Pass
+        );
+
+        let warned = score(&counts(10, 20, 0, 100), Some(100), &policy);
+        assert_eq!(warned.grade, Grade::Warn);
+        assert_eq!(warned.reasons, "inserted 0.200 > 0.1");
+
+        let failed = score(&counts(0, 0, 30, 100), Some(200), &policy);
+        assert_eq!(failed.grade, Grade::Fail);
+        assert_eq!(failed.length_ratio, Some(0.5));
+        assert_eq!(
+            failed.reasons,
+            "moved 0.300 > 0.25;length ratio 0.500 < 0.8"
+        );
+
+        assert_eq!(score(&counts(0, 0, 0, 0), None, &policy).grade, Grade::Fail);
+
+        let lenient: Policy = serde_json::from_str(r#"{"fail": {"max_moved": 0.5}}"#)?;
+        let lenient = score(&counts(0, 0, 30, 100), Some(200), &lenient);
+        assert_eq!(lenient.grade, Grade::Warn);
+        assert_eq!(
+            lenient.reasons,
+            "moved 0.300 > 0.1;length ratio 0.500 < 0.95"
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_unknown_policy_keys() {
+        assert!(serde_json::from_str::<Policy>(r#"{"fail": {"max_chnged": 0.5}}"#).is_err());
+        assert!(serde_json::from_str::<Policy>(r#"{"error": {}}"#).is_err());
+    }
+}
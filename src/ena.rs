@@ -1,3 +1,8 @@
+//! Fetch taxonomy information from the ENA browser API.
+
+use std::collections::{HashMap, HashSet};
+use std::{thread, time};
+
 use log::info;
 
 use serde::{Deserialize, Serialize};
@@ -6,7 +11,9 @@ use serde_xml_rs::from_reader;
 
 use anyhow::Result;
 
-#[derive(Debug, Deserialize, Serialize)]
+pub mod cache;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EnaLineageTaxon {
     #[serde(rename = "scientificName")]
     pub name: String,
@@ -16,13 +23,13 @@ pub struct EnaLineageTaxon {
     pub rank: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EnaLineage {
     #[serde(rename = "taxon")]
-    taxons: Vec<EnaLineageTaxon>,
+    pub taxons: Vec<EnaLineageTaxon>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EnaTaxonInfo {
     #[serde(rename = "scientificName")]
     pub name: String,
@@ -45,6 +52,7 @@ struct TaxonSet {
     taxons: Vec<EnaTaxonInfo>,
 }
 
+/// Fetch the info for all taxids ENA knows about.
 pub fn species(taxids: &[usize]) -> Result<Vec<EnaTaxonInfo>> {
     let string_taxids: Vec<String> = taxids.iter().map(|t| t.to_string()).collect();
     let tids = string_taxids.join(",");
@@ -62,3 +70,37 @@ pub fn species(taxids: &[usize]) -> Result<Vec<EnaTaxonInfo>> {
     }
     return Ok(info);
 }
+
+/// Fetch the info for each requested taxid, keyed by the requested taxid, and
+/// the taxids which ENA does not know about. ENA returns merged taxids under
+/// their current taxid, so any taxid not found in the first request is
+/// requested alone, which lets merged taxids be matched to the taxon they now
+/// belong to. Taxids which are not found alone are unknown, as ENA does not
+/// say if they were deleted or never existed.
+pub fn lookup(taxids: &[usize]) -> Result<(HashMap<usize, EnaTaxonInfo>, HashSet<usize>)> {
+    let mut found: HashMap<usize, EnaTaxonInfo> = species(taxids)?
+        .into_iter()
+        .filter(|t| taxids.contains(&t.taxid))
+        .map(|t| (t.taxid, t))
+        .collect();
+
+    let missing: Vec<usize> = taxids
+        .iter()
+        .filter(|t| !found.contains_key(t))
+        .cloned()
+        .collect();
+    let mut unknown = HashSet::new();
+    for taxid in missing {
+        thread::sleep(time::Duration::from_millis(200));
+        match species(&[taxid])?.into_iter().next() {
+            Some(current) => {
+                info!("Taxid {} has been merged into {}", taxid, current.taxid);
+                found.insert(taxid, current);
+            }
+            None => {
+                unknown.insert(taxid);
+            }
+        }
+    }
+    return Ok((found, unknown));
+}
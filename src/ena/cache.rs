@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::ena::{lookup, EnaTaxonInfo};
+use crate::fs::write_atomically;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    /// Use cached entries and fetch anything not yet seen.
+    Normal,
+    /// Never contact ENA, taxids not in the cache are not found.
+    CacheOnly,
+    /// Fetch every requested taxid again and replace the cached entry.
+    Refresh,
+}
+
+/// One line of the cache file. Taxids ENA did not know about are stored with
+/// no info, so they are not requested again. Merged taxids are stored with the
+/// info of the taxon they were merged into.
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    taxid: usize,
+    fetched: u64,
+    info: Option<EnaTaxonInfo>,
+}
+
+pub struct TaxonomyCache {
+    entries: HashMap<usize, CacheEntry>,
+    writer: BufWriter<File>,
+    mode: CacheMode,
+    expire_after: Option<Duration>,
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+}
+
+impl CacheEntry {
+    fn is_expired(&self, expire_after: &Option<Duration>, now: u64) -> bool {
+        return match expire_after {
+            None => false,
+            Some(age) => self.fetched + age.as_secs() < now,
+        };
+    }
+}
+
+impl TaxonomyCache {
+    /// Open the cache at `path`, creating it if needed. Entries are only ever
+    /// appended, so a file with entries replaced by later ones is compacted
+    /// to the latest entry for each taxid as it is loaded.
+    pub fn open(path: &PathBuf, mode: CacheMode, expire_after: Option<Duration>) -> Result<Self> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            let mut lines = 0;
+            for line in reader.lines() {
+                let entry: CacheEntry = serde_json::from_str(&line?)?;
+                entries.insert(entry.taxid, entry);
+                lines += 1;
+            }
+            info!("Loaded {} cached taxids from {:?}", entries.len(), path);
+            if lines > entries.len() {
+                info!("Compacting {} replaced entries", lines - entries.len());
+                compact(path, &entries)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(Self {
+            entries,
+            writer: BufWriter::new(file),
+            mode,
+            expire_after,
+        });
+    }
+
+    /// The requested taxids which have to be fetched from ENA.
+    fn to_fetch(&self, taxids: &[usize], now: u64) -> Vec<usize> {
+        return taxids
+            .iter()
+            .filter(|t| self.needs_fetch(t, now))
+            .cloned()
+            .collect();
+    }
+
+    fn needs_fetch(&self, taxid: &usize, now: u64) -> bool {
+        return match self.mode {
+            CacheMode::CacheOnly => false,
+            CacheMode::Refresh => true,
+            CacheMode::Normal => match self.entries.get(taxid) {
+                None => true,
+                Some(entry) => entry.is_expired(&self.expire_after, now),
+            },
+        };
+    }
+
+    /// Find the info for all given taxids, fetching only those which are not
+    /// cached. Returns the known taxa, keyed by the requested taxid, the
+    /// taxids ENA does not know about, and if ENA had to be queried.
+    pub fn lookup(
+        &mut self,
+        taxids: &[usize],
+    ) -> Result<(HashMap<usize, EnaTaxonInfo>, HashSet<usize>, bool)> {
+        return self.lookup_with(taxids, lookup);
+    }
+
+    /// The same as `lookup`, but taxids which are not cached are found with
+    /// `fetch` instead of ENA.
+    fn lookup_with<F>(
+        &mut self,
+        taxids: &[usize],
+        fetch: F,
+    ) -> Result<(HashMap<usize, EnaTaxonInfo>, HashSet<usize>, bool)>
+    where
+        F: FnOnce(&[usize]) -> Result<(HashMap<usize, EnaTaxonInfo>, HashSet<usize>)>,
+    {
+        let now = now();
+        let to_fetch = self.to_fetch(taxids, now);
+        let fetched = !to_fetch.is_empty();
+        if fetched {
+            let (mut found, _) = fetch(&to_fetch)?;
+            for taxid in to_fetch {
+                let entry = CacheEntry {
+                    taxid,
+                    fetched: now,
+                    info: found.remove(&taxid),
+                };
+                writeln!(self.writer, "{}", serde_json::to_string(&entry)?)?;
+                self.entries.insert(taxid, entry);
+            }
+            self.writer.flush()?;
+        }
+
+        let mut known = HashMap::new();
+        let mut unknown = HashSet::new();
+        for taxid in taxids {
+            let entry = match self.entries.get(taxid) {
+                Some(e) => e,
+                None => continue,
+            };
+            if self.mode == CacheMode::CacheOnly && entry.is_expired(&self.expire_after, now) {
+                continue;
+            }
+            match &entry.info {
+                Some(info) => {
+                    known.insert(*taxid, info.clone());
+                }
+                None => {
+                    unknown.insert(*taxid);
+                }
+            }
+        }
+        return Ok((known, unknown, fetched));
+    }
+}
+
+/// Replace the cache file with only the given entries, in taxid order.
+fn compact(path: &Path, entries: &HashMap<usize, CacheEntry>) -> Result<()> {
+    let mut taxids: Vec<&usize> = entries.keys().collect();
+    taxids.sort();
+    return write_atomically(path, |file| {
+        let mut writer = BufWriter::new(file);
+        for taxid in taxids {
+            writeln!(writer, "{}", serde_json::to_string(&entries[taxid])?)?;
+        }
+        writer.flush()?;
+        return Ok(());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn taxon(taxid: usize) -> EnaTaxonInfo {
+        return EnaTaxonInfo {
+            name: format!("taxon {}", taxid),
+            taxid,
+            rank: Some(String::from("species")),
+            lineage: crate::ena::EnaLineage { taxons: Vec::new() },
+        };
+    }
+
+    /// Look up taxids, recording which were fetched. Fetched taxids below 100
+    /// are found, the rest are unknown.
+    fn fetching(cache: &mut TaxonomyCache, taxids: &[usize]) -> Result<(Vec<usize>, usize)> {
+        let mut requested = Vec::new();
+        let (found, _, _) = cache.lookup_with(taxids, |to_fetch| {
+            requested.extend_from_slice(to_fetch);
+            let found = to_fetch
+                .iter()
+                .filter(|t| **t < 100)
+                .map(|t| (*t, taxon(*t)))
+                .collect();
+            return Ok((found, HashSet::new()));
+        })?;
+        return Ok((requested, found.len()));
+    }
+
+    #[test]
+    fn expires_old_entries() {
+        let entry = CacheEntry {
+            taxid: 9606,
+            fetched: 100,
+            info: None,
+        };
+        assert!(!entry.is_expired(&None, 1000));
+        assert!(!entry.is_expired(&Some(Duration::from_secs(900)), 1000));
+        assert!(entry.is_expired(&Some(Duration::from_secs(899)), 1000));
+    }
+
+    #[test]
+    fn only_fetches_taxids_not_seen_before() -> Result<()> {
+        let dir = TempDir::new("cache")?;
+        let path = dir.path().join("cache.jsonl");
+        let mut cache = TaxonomyCache::open(&path, CacheMode::Normal, None)?;
+        assert_eq!(fetching(&mut cache, &[1, 2, 200])?, (vec![1, 2, 200], 2));
+        assert_eq!(fetching(&mut cache, &[1, 2, 3, 200])?, (vec![3], 3));
+
+        let (found, unknown, fetched) = TaxonomyCache::open(&path, CacheMode::Normal, None)?
+            .lookup_with(&[1, 200], |_| panic!("Nothing should be fetched"))?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(unknown, vec![200].into_iter().collect());
+        assert!(!fetched);
+        return Ok(());
+    }
+
+    #[test]
+    fn never_fetches_in_cache_only_mode() -> Result<()> {
+        let dir = TempDir::new("cache-only")?;
+        let path = dir.path().join("cache.jsonl");
+        fetching(
+            &mut TaxonomyCache::open(&path, CacheMode::Normal, None)?,
+            &[1],
+        )?;
+
+        let mut cache = TaxonomyCache::open(&path, CacheMode::CacheOnly, None)?;
+        assert_eq!(fetching(&mut cache, &[1, 2])?, (Vec::new(), 1));
+        return Ok(());
+    }
+
+    #[test]
+    fn refreshes_and_compacts_entries() -> Result<()> {
+        let dir = TempDir::new("refresh")?;
+        let path = dir.path().join("cache.jsonl");
+        fetching(
+            &mut TaxonomyCache::open(&path, CacheMode::Normal, None)?,
+            &[1, 2],
+        )?;
+
+        let mut cache = TaxonomyCache::open(&path, CacheMode::Refresh, None)?;
+        assert_eq!(fetching(&mut cache, &[1, 2])?, (vec![1, 2], 2));
+        drop(cache);
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 4);
+
+        TaxonomyCache::open(&path, CacheMode::Normal, None)?;
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 2);
+        return Ok(());
+    }
+}
@@ -1,116 +1,922 @@
-use std::collections::HashSet;
+//! Find and fix problems with the naming and location of files in the tree
+//! of diagrams.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
+use flate2::read::GzDecoder;
+
+use quick_xml::Reader;
+
 use serde::{Deserialize, Serialize};
 
 use walkdir::WalkDir;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+use crate::coloring;
+use crate::layout::{Layout, Scheme, Sharding};
+use crate::urs::UrsId;
+
+use summary::{FailPolicy, Summary};
 
+pub mod apply;
+pub mod summary;
 pub mod urs_utils;
 
+/// The state of a single file in the tree, or of a required URS which was
+/// not found.
 #[derive(Serialize, Deserialize, Debug)]
-enum UrsStatus {
+pub enum UrsStatus {
     CorrectSvg {
-        urs: String,
+        urs: UrsId,
     },
     MissingSvg {
-        urs: String,
+        urs: UrsId,
     },
+    /// A diagram for a URS which is not required.
     ExtraSvg {
-        urs: String,
+        urs: UrsId,
         found_at: PathBuf,
     },
     MoveSvg {
-        urs: String,
+        urs: UrsId,
         found_at: PathBuf,
         expected_path: PathBuf,
     },
     CompressSvg {
-        urs: String,
+        urs: UrsId,
         found_at: PathBuf,
         expected_path: PathBuf,
     },
+    /// A diagram stored where another layout would place it. The expected
+    /// path is unknown when moving into a model family layout, as the model
+    /// is not known.
+    WrongLayout {
+        urs: UrsId,
+        found_at: PathBuf,
+        layout: String,
+        expected_path: Option<PathBuf>,
+    },
+    /// A diagram which cannot be fully decompressed, usually as it was
+    /// truncated.
+    CorruptSvg {
+        urs: UrsId,
+        found_at: PathBuf,
+        reason: String,
+    },
+    /// A diagram with no content. This and the following statuses are only
+    /// produced by a deep check.
+    EmptySvg {
+        urs: UrsId,
+        found_at: PathBuf,
+    },
+    MalformedSvg {
+        urs: UrsId,
+        found_at: PathBuf,
+        reason: String,
+    },
+    /// A diagram without any colored nucleotides.
+    NoNucleotides {
+        urs: UrsId,
+        found_at: PathBuf,
+    },
+    /// A diagram which contains a different URS than its filename.
+    UrsMismatch {
+        urs: UrsId,
+        found_at: PathBuf,
+        embedded: UrsId,
+    },
+    /// One of several copies of a required diagram, which is not the copy
+    /// chosen to keep.
+    DuplicateSvg {
+        urs: UrsId,
+        found_at: PathBuf,
+        canonical: PathBuf,
+        locations: Vec<PathBuf>,
+    },
     UnknownFile {
         path: PathBuf,
     },
 }
 
-fn load_required(path: PathBuf) -> Result<HashSet<String>> {
-    let mut known = HashSet::new();
-    let file = File::open(path)?;
+/// Every status which can appear in a report, in the order of the variants.
+pub const STATUS_NAMES: [&str; 13] = [
+    "CorrectSvg",
+    "MissingSvg",
+    "ExtraSvg",
+    "MoveSvg",
+    "CompressSvg",
+    "WrongLayout",
+    "CorruptSvg",
+    "EmptySvg",
+    "MalformedSvg",
+    "NoNucleotides",
+    "UrsMismatch",
+    "DuplicateSvg",
+    "UnknownFile",
+];
+
+impl UrsStatus {
+    /// The name of the variant, as used in reports.
+    pub fn name(&self) -> &'static str {
+        let index = match self {
+            Self::CorrectSvg { .. } => 0,
+            Self::MissingSvg { .. } => 1,
+            Self::ExtraSvg { .. } => 2,
+            Self::MoveSvg { .. } => 3,
+            Self::CompressSvg { .. } => 4,
+            Self::WrongLayout { .. } => 5,
+            Self::CorruptSvg { .. } => 6,
+            Self::EmptySvg { .. } => 7,
+            Self::MalformedSvg { .. } => 8,
+            Self::NoNucleotides { .. } => 9,
+            Self::UrsMismatch { .. } => 10,
+            Self::DuplicateSvg { .. } => 11,
+            Self::UnknownFile { .. } => 12,
+        };
+        return STATUS_NAMES[index];
+    }
+
+    /// The file this status is about, `None` for missing diagrams.
+    pub fn found_at(&self) -> Option<&Path> {
+        return match self {
+            Self::CorrectSvg { .. } | Self::MissingSvg { .. } => None,
+            Self::ExtraSvg { found_at, .. }
+            | Self::MoveSvg { found_at, .. }
+            | Self::CompressSvg { found_at, .. }
+            | Self::WrongLayout { found_at, .. }
+            | Self::CorruptSvg { found_at, .. }
+            | Self::EmptySvg { found_at, .. }
+            | Self::MalformedSvg { found_at, .. }
+            | Self::NoNucleotides { found_at, .. }
+            | Self::UrsMismatch { found_at, .. }
+            | Self::DuplicateSvg { found_at, .. } => Some(found_at),
+            Self::UnknownFile { path } => Some(path),
+        };
+    }
+
+    /// Check if this status indicates something is wrong with the tree.
+    pub fn is_problem(&self) -> bool {
+        return !matches!(self, Self::CorrectSvg { .. });
+    }
+}
+
+pub struct CheckOptions {
+    pub layout: Layout,
+    /// Decompress and parse every diagram, instead of only checking paths.
+    pub deep: bool,
+    /// Decompress every compressed diagram to find damaged files, without
+    /// parsing it. Implied by `deep`.
+    pub check_gzip: bool,
+    /// Directories inside the tree which are not checked, such as the
+    /// quarantine. Otherwise the model family layout treats them as models.
+    pub exclude: Vec<PathBuf>,
+}
+
+/// How the results of a check are reported.
+pub struct ReportOptions {
+    /// Print only totals instead of a line per file.
+    pub summary: bool,
+    /// The number of directories with the most problems to list.
+    pub top: usize,
+    /// How many directories below the base to group the summary by.
+    pub shard_depth: usize,
+    pub fail_on: FailPolicy,
+}
+
+/// Load a file of URS ids, with or without a taxid, one per line, in sorted
+/// order so reports are stable.
+pub fn load_required(path: PathBuf) -> Result<BTreeSet<UrsId>> {
+    let mut known = BTreeSet::new();
+    let file = File::open(&path)?;
     let reader = BufReader::new(file);
-    for line in reader.lines() {
-        let line = line?.trim().to_owned();
-        known.insert(line);
+    for (index, line) in reader.lines().enumerate() {
+        let urs = line?
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Line {} of {:?}: {}", index + 1, path, e))?;
+        known.insert(urs);
     }
     return Ok(known);
 }
 
-fn compare_paths(base: &PathBuf, urs: &String, path: &Path) -> UrsStatus {
-    let expected_path = urs_utils::path_for(&base, urs);
+/// The model a diagram at `path` was drawn with, if `layout` stores diagrams
+/// by model. This is the name of the directory containing it, or the one
+/// above the taxid directory when diagrams are also split by taxid. Other
+/// layouts have no model directory, so no model is inferred.
+pub(crate) fn layout_model<'a>(path: &'a Path, urs: &UrsId, layout: &Layout) -> Option<&'a str> {
+    if layout.scheme != Scheme::ModelFamily {
+        return None;
+    }
+    let mut directory = path.parent();
+    if layout.sharding == Sharding::UrsTaxid && urs.taxid().is_some() {
+        directory = directory.and_then(|p| p.parent());
+    }
+    return directory
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str());
+}
+
+/// Determine the status of a file at `path` which contains the diagram for
+/// `urs`.
+pub fn compare_paths(base: &Path, urs: &UrsId, path: &Path, layout: &Layout) -> UrsStatus {
+    let model = layout_model(path, urs, layout);
+    let expected_path = layout.path_for(base, urs, model);
     if expected_path == path {
-        return UrsStatus::CorrectSvg {
-            urs: urs.to_string(),
-        };
+        return UrsStatus::CorrectSvg { urs: *urs };
     }
 
-    let uncompressed_path = urs_utils::uncompressed_path(&base, &urs);
-    if uncompressed_path == path {
+    if layout.compressed && layout.uncompressed().path_for(base, urs, model) == path {
         return UrsStatus::CompressSvg {
-            urs: urs.to_string(),
+            urs: *urs,
             found_at: PathBuf::from(path),
             expected_path,
         };
     }
 
-    for possible in urs_utils::incorrect_paths(&base, &urs) {
+    for possible in layout.incorrect_paths(base, urs, model) {
         if possible == path {
             return UrsStatus::MoveSvg {
-                urs: urs.to_string(),
+                urs: *urs,
                 found_at: possible,
                 expected_path,
             };
         }
     }
 
+    for other in layout.alternatives() {
+        if other.path_for(base, urs, layout_model(path, urs, &other)) == path {
+            let known_model = other.scheme == Scheme::ModelFamily;
+            return UrsStatus::WrongLayout {
+                urs: *urs,
+                found_at: PathBuf::from(path),
+                layout: other.to_string(),
+                expected_path: match (layout.scheme, known_model) {
+                    (Scheme::ModelFamily, false) => None,
+                    _ => Some(expected_path),
+                },
+            };
+        }
+    }
+
     return UrsStatus::UnknownFile {
         path: PathBuf::from(path),
     };
 }
 
-pub fn write_report(base: &PathBuf, required_file: PathBuf) -> Result<()> {
-    let mut required = load_required(required_file)?;
+/// The reason a gzip compressed file cannot be read to the end, if any.
+pub fn gzip_error(path: &Path) -> Option<String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(e.to_string()),
+    };
+    let mut decoder = GzDecoder::new(BufReader::new(file));
+    return io::copy(&mut decoder, &mut io::sink())
+        .err()
+        .map(|e| e.to_string());
+}
+
+/// Check if the identifiers refer to different sequences. Taxids are only
+/// compared when both have one.
+fn is_mismatch(urs: &UrsId, embedded: &UrsId) -> bool {
+    return match (urs.taxid(), embedded.taxid()) {
+        (Some(a), Some(b)) => urs != embedded || a != b,
+        _ => urs.urs() != embedded.urs(),
+    };
+}
+
+/// Find problems with the content of a diagram, or `None` if there are none.
+fn check_content(urs: &UrsId, path: &Path, content: &[u8]) -> Option<UrsStatus> {
+    let found_at = PathBuf::from(path);
+    if content.iter().all(|b| b.is_ascii_whitespace()) {
+        return Some(UrsStatus::EmptySvg {
+            urs: *urs,
+            found_at,
+        });
+    }
+    let check = match coloring::check_reader(&mut Reader::from_reader(content)) {
+        Ok(c) => c,
+        Err(e) => {
+            return Some(UrsStatus::MalformedSvg {
+                urs: *urs,
+                found_at,
+                reason: e.to_string(),
+            })
+        }
+    };
+    if let Some(embedded) = check.identifier {
+        if is_mismatch(urs, &embedded) {
+            return Some(UrsStatus::UrsMismatch {
+                urs: *urs,
+                found_at,
+                embedded,
+            });
+        }
+    }
+    if check.nucleotides == 0 {
+        return Some(UrsStatus::NoNucleotides {
+            urs: *urs,
+            found_at,
+        });
+    }
+    return None;
+}
+
+/// Read the complete, decompressed, content of a diagram.
+fn read_diagram(path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    let mut file = BufReader::new(File::open(path)?);
+    match is_compressed(path) {
+        true => GzDecoder::new(file).read_to_end(&mut content)?,
+        false => file.read_to_end(&mut content)?,
+    };
+    return Ok(content);
+}
+
+fn is_compressed(path: &Path) -> bool {
+    return path.extension().is_some_and(|e| e == "gz");
+}
+
+/// The status of a required diagram. When checking gzip files this is corrupt
+/// if it cannot be decompressed, and with a deep check any problem with the
+/// content is reported before problems with the path. Files with no bytes at
+/// all are empty, even if they should be compressed.
+fn check_diagram(base: &Path, urs: &UrsId, path: &Path, options: &CheckOptions) -> UrsStatus {
+    let checks_content = options.deep || options.check_gzip;
+    if checks_content && path.metadata().is_ok_and(|m| m.len() == 0) {
+        return UrsStatus::EmptySvg {
+            urs: *urs,
+            found_at: PathBuf::from(path),
+        };
+    }
+    if options.deep {
+        let problem = match read_diagram(path) {
+            Ok(content) => check_content(urs, path, &content),
+            Err(e) => Some(UrsStatus::CorruptSvg {
+                urs: *urs,
+                found_at: PathBuf::from(path),
+                reason: e.to_string(),
+            }),
+        };
+        if let Some(status) = problem {
+            return status;
+        }
+    } else if options.check_gzip && is_compressed(path) {
+        if let Some(reason) = gzip_error(path) {
+            return UrsStatus::CorruptSvg {
+                urs: *urs,
+                found_at: PathBuf::from(path),
+                reason,
+            };
+        }
+    }
+    return compare_paths(base, urs, path, &options.layout);
+}
+
+/// How suitable a copy of a diagram is to keep, lower is better.
+fn copy_rank(status: &UrsStatus) -> usize {
+    return match status {
+        UrsStatus::CorrectSvg { .. } => 0,
+        UrsStatus::CompressSvg { .. }
+        | UrsStatus::MoveSvg { .. }
+        | UrsStatus::WrongLayout {
+            expected_path: Some(_),
+            ..
+        } => 1,
+        UrsStatus::WrongLayout { .. } => 2,
+        UrsStatus::UnknownFile { .. } => 3,
+        UrsStatus::NoNucleotides { .. } | UrsStatus::UrsMismatch { .. } => 4,
+        _ => 5,
+    };
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    return fs::metadata(path).and_then(|m| m.modified()).ok();
+}
+
+/// The status of every copy of a required diagram. When there are several
+/// the best copy, preferring the most recently modified, is kept and every
+/// other copy is a duplicate of it. Duplicates come first, so they are moved
+/// out of the way before the kept copy is fixed.
+fn check_copies(
+    base: &Path,
+    urs: &UrsId,
+    mut paths: Vec<PathBuf>,
+    options: &CheckOptions,
+) -> Vec<UrsStatus> {
+    paths.sort();
+    let mut statuses: Vec<UrsStatus> = paths
+        .iter()
+        .map(|path| check_diagram(base, urs, path, options))
+        .collect();
+    if statuses.len() == 1 {
+        return statuses;
+    }
+
+    let best = (0..paths.len())
+        .min_by_key(|&i| (copy_rank(&statuses[i]), Reverse(modified(&paths[i]))))
+        .unwrap();
+    let kept = statuses.remove(best);
+    let canonical = paths[best].clone();
+    let mut copies: Vec<UrsStatus> = paths
+        .iter()
+        .filter(|path| **path != canonical)
+        .map(|path| UrsStatus::DuplicateSvg {
+            urs: *urs,
+            found_at: path.clone(),
+            canonical: canonical.clone(),
+            locations: paths.clone(),
+        })
+        .collect();
+    copies.push(kept);
+    return copies;
+}
+
+/// Check if the directory at `path` is one of the canonical `exclude`
+/// directories, or inside one, however the tree was given.
+fn is_excluded(path: &Path, exclude: &[PathBuf]) -> bool {
+    if exclude.is_empty() {
+        return false;
+    }
+    return match path.canonicalize() {
+        Ok(path) => exclude.iter().any(|d| path.starts_with(d)),
+        Err(_) => false,
+    };
+}
+
+/// Every path where one of the layouts puts the diagram for `urs`, with
+/// models taken from the directories of `path`.
+fn known_locations(base: &Path, urs: &UrsId, path: &Path, layout: &Layout) -> Vec<PathBuf> {
+    let mut paths = layout.incorrect_paths(base, urs, layout_model(path, urs, layout));
+    let mut layouts = vec![*layout];
+    layouts.extend(layout.alternatives());
+    for other in layouts {
+        paths.push(other.path_for(base, urs, layout_model(path, urs, &other)));
+    }
+    return paths;
+}
+
+/// The copies of a required diagram and the one which was kept.
+struct Copies {
+    canonical: PathBuf,
+    locations: Vec<PathBuf>,
+}
+
+/// The status of a group of copies of a required diagram found next to each
+/// other in the walk. Unless the group is a single correct copy, every other
+/// place a layout would put the diagram is checked too, so the kept copy does
+/// not depend on which copy the walk found first. Returns the statuses and
+/// the copies, when they can not be worked out again from the layout.
+fn check_group(
+    base: &Path,
+    urs: &UrsId,
+    mut paths: Vec<PathBuf>,
+    options: &CheckOptions,
+    exclude: &[PathBuf],
+) -> (Vec<UrsStatus>, Option<Copies>) {
+    let layout = &options.layout;
+    let expected = layout.path_for(base, urs, layout_model(&paths[0], urs, layout));
+    if paths.len() == 1 && paths[0] == expected {
+        let status = check_diagram(base, urs, &expected, options);
+        if copy_rank(&status) == 0 && layout.scheme != Scheme::ModelFamily {
+            return (vec![status], None);
+        }
+    }
+
+    for path in known_locations(base, urs, &paths[0], layout) {
+        let excluded = path.parent().is_some_and(|p| is_excluded(p, exclude));
+        if !paths.contains(&path) && !excluded && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let statuses = check_copies(base, urs, paths.clone(), options);
+    let canonical = statuses
+        .last()
+        .and_then(|s| s.found_at())
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    return (
+        statuses,
+        Some(Copies {
+            canonical,
+            locations: paths,
+        }),
+    );
+}
+
+/// Report a finished group of copies of a required diagram, and remember
+/// which copy was kept.
+fn finish_group<F>(
+    base: &Path,
+    options: &CheckOptions,
+    exclude: &[PathBuf],
+    group: &mut Vec<PathBuf>,
+    required: &mut BTreeMap<UrsId, bool>,
+    copies: &mut HashMap<UrsId, Copies>,
+    handle: &mut F,
+) -> Result<()>
+where
+    F: FnMut(UrsStatus) -> Result<()>,
+{
+    let urs = match group.first().and_then(|p| urs_utils::filename_urs(p)) {
+        Some(urs) => urs,
+        None => return Ok(()),
+    };
+    required.insert(urs, true);
+    let (statuses, found) = check_group(base, &urs, std::mem::take(group), options, exclude);
+    if let Some(found) = found {
+        copies.insert(urs, found);
+    }
+    for status in statuses {
+        handle(status)?;
+    }
+    return Ok(());
+}
+
+/// Call `handle` with the status of every file in the tree under `base`, and
+/// then for every required URS which was not found. The walk is sorted, so
+/// copies of a diagram in one directory are next to each other and each
+/// group of them is reported, with every known location of the diagram,
+/// once it ends. Copies are only remembered for diagrams which were not a
+/// single correct file, so a copy found later somewhere no layout would put
+/// it is reported as a duplicate of the kept one.
+pub fn each_status<F>(
+    base: &Path,
+    options: &CheckOptions,
+    required_file: PathBuf,
+    mut handle: F,
+) -> Result<()>
+where
+    F: FnMut(UrsStatus) -> Result<()>,
+{
+    let mut required: BTreeMap<UrsId, bool> = load_required(required_file)?
+        .into_iter()
+        .map(|urs| (urs, false))
+        .collect();
+    let exclude = options
+        .exclude
+        .iter()
+        .map(|d| crate::fs::canonical(d))
+        .collect::<Result<Vec<PathBuf>>>()?;
     let walker = WalkDir::new(PathBuf::from(base))
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
         .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && is_excluded(e.path(), &exclude)))
         .filter_map(Result::ok);
 
+    let mut copies: HashMap<UrsId, Copies> = HashMap::new();
+    let mut group: Vec<PathBuf> = Vec::new();
     for dir_entry in walker {
+        if !dir_entry.file_type().is_file() {
+            continue;
+        }
         let path = dir_entry.path();
-        let status = match urs_utils::filename_urs(&path) {
-            None => UrsStatus::UnknownFile {
-                path: PathBuf::from(path),
-            },
-            Some(urs) => match required.remove(&urs) {
-                true => compare_paths(&base, &urs, &path),
-                false => UrsStatus::UnknownFile {
-                    path: PathBuf::from(path),
-                },
-            },
-        };
+        let urs = urs_utils::filename_urs(path);
+        let same_group = group.first().is_some_and(|first| {
+            first.parent() == path.parent() && urs_utils::filename_urs(first) == urs
+        });
+        if !same_group {
+            finish_group(
+                base,
+                options,
+                &exclude,
+                &mut group,
+                &mut required,
+                &mut copies,
+                &mut handle,
+            )?;
+        }
 
-        let json = serde_json::to_string(&status)?;
-        println!("{}", json);
+        match urs.map(|urs| (urs, required.get(&urs))) {
+            Some((_, Some(false))) => group.push(PathBuf::from(path)),
+            Some((urs, Some(true))) => {
+                let found = copies.entry(urs).or_insert_with(|| {
+                    let canonical = options.layout.path_for(base, &urs, None);
+                    Copies {
+                        locations: vec![canonical.clone()],
+                        canonical,
+                    }
+                });
+                if found.locations.iter().any(|p| p == path) {
+                    continue;
+                }
+                found.locations.push(PathBuf::from(path));
+                handle(UrsStatus::DuplicateSvg {
+                    urs,
+                    found_at: PathBuf::from(path),
+                    canonical: found.canonical.clone(),
+                    locations: found.locations.clone(),
+                })?
+            }
+            Some((urs, None)) => handle(UrsStatus::ExtraSvg {
+                urs,
+                found_at: PathBuf::from(path),
+            })?,
+            None => handle(UrsStatus::UnknownFile {
+                path: PathBuf::from(path),
+            })?,
+        }
     }
+    finish_group(
+        base,
+        options,
+        &exclude,
+        &mut group,
+        &mut required,
+        &mut copies,
+        &mut handle,
+    )?;
 
-    for urs in required {
-        let status = UrsStatus::MissingSvg { urs };
-        let json = serde_json::to_string(&status)?;
-        println!("{}", json);
+    for (urs, found) in required {
+        if !found {
+            handle(UrsStatus::MissingSvg { urs })?;
+        }
     }
 
     return Ok(());
 }
+
+/// Compute the status of every file in the tree.
+pub fn report(
+    base: &Path,
+    options: &CheckOptions,
+    required_file: PathBuf,
+) -> Result<Vec<UrsStatus>> {
+    let mut statuses = Vec::new();
+    each_status(base, options, required_file, |status| {
+        statuses.push(status);
+        return Ok(());
+    })?;
+    return Ok(statuses);
+}
+
+/// Write the status of every file as a JSON line to `out`, or only a summary
+/// of them. Fails once everything is written if the report violates the fail
+/// policy.
+pub fn write_report<W: Write>(
+    base: &Path,
+    options: &CheckOptions,
+    required_file: PathBuf,
+    report: &ReportOptions,
+    mut out: W,
+) -> Result<()> {
+    let mut summary = Summary::default();
+    each_status(base, options, required_file, |status| {
+        if !report.summary {
+            let json = serde_json::to_string(&status)?;
+            writeln!(out, "{}", json)?;
+        }
+        summary.record(base, &options.layout, &status, report.shard_depth);
+        return Ok(());
+    })?;
+
+    summary.finish(report.top);
+    if report.summary {
+        writeln!(out, "{}", serde_json::to_string_pretty(&summary)?)?;
+    }
+    return report.fail_on.check(&summary);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{check_options, TempDir};
+
+    #[test]
+    fn detects_files_in_other_layouts() -> Result<()> {
+        let base = PathBuf::from("foo");
+        let urs: UrsId = "URS0000000372".parse()?;
+        let layout = Layout::default();
+        match compare_paths(&base, &urs, Path::new("foo/URS0000000372.svg"), &layout) {
+            UrsStatus::WrongLayout {
+                layout,
+                expected_path,
+                ..
+            } => {
+                assert_eq!(layout, "flat,uncompressed");
+                assert_eq!(
+                    expected_path,
+                    Some(PathBuf::from("foo/URS/00/00/00/03/URS0000000372.svg.gz"))
+                );
+            }
+            status => panic!("Unexpected status {:?}", status),
+        }
+
+        let family: Layout = "model-family".parse()?;
+        let path = Path::new("foo/RF00001/URS0000000372.svg.gz");
+        assert!(matches!(
+            compare_paths(&base, &urs, path, &family),
+            UrsStatus::CorrectSvg { .. }
+        ));
+        assert!(matches!(
+            compare_paths(&base, &urs, path, &layout),
+            UrsStatus::WrongLayout { .. }
+        ));
+        return Ok(());
+    }
+
+    #[test]
+    fn infers_models_only_for_model_family() -> Result<()> {
+        let urs: UrsId = "URS0000000372_9606".parse()?;
+        let path = Path::new("foo/RF00001/9606/URS0000000372_9606.svg.gz");
+        assert_eq!(layout_model(path, &urs, &"flat".parse()?), None);
+        assert_eq!(layout_model(path, &urs, &Layout::default()), None);
+
+        let family: Layout = "model-family,by-taxid".parse()?;
+        assert_eq!(layout_model(path, &urs, &family), Some("RF00001"));
+        assert!(matches!(
+            compare_paths(Path::new("foo"), &urs, path, &family),
+            UrsStatus::CorrectSvg { .. }
+        ));
+        return Ok(());
+    }
+
+    #[test]
+    fn checks_diagram_content() -> Result<()> {
+        let urs: UrsId = "URS0000000372_9606".parse()?;
+        let path = Path::new("URS0000000372_9606.svg");
+        let status = |content: &str| check_content(&urs, path, content.as_bytes());
+
+        let good = r#"<svg><title>URS0000000372_9606</title><text class="red">A</text></svg>"#;
+        assert!(status(good).is_none());
+        assert!(matches!(status(" \n"), Some(UrsStatus::EmptySvg { .. })));
+        assert!(matches!(
+            status("<svg><g><text class=\"red\">A</text></svg>"),
+            Some(UrsStatus::MalformedSvg { .. })
+        ));
+        assert!(matches!(
+            status("<svg><g><text class=\"red\">A</text></g>"),
+            Some(UrsStatus::MalformedSvg { .. })
+        ));
+        assert!(matches!(
+            status("<svg><text>URS0000000372</text></svg>"),
+            Some(UrsStatus::NoNucleotides { .. })
+        ));
+        assert!(matches!(
+            status(r#"<svg id="URS0000000373"><text class="red">A</text></svg>"#),
+            Some(UrsStatus::UrsMismatch { .. })
+        ));
+        assert!(matches!(
+            status(r#"<svg id="URS0000000372_562"><text class="red">A</text></svg>"#),
+            Some(UrsStatus::UrsMismatch { .. })
+        ));
+        return Ok(());
+    }
+
+    #[test]
+    fn reports_empty_compressed_diagrams() -> Result<()> {
+        let dir = TempDir::new("empty")?;
+        let path = dir.write("URS0000000372.svg.gz", "")?;
+        let urs: UrsId = "URS0000000372".parse()?;
+        let mut options = check_options("flat")?;
+        options.deep = true;
+        assert!(matches!(
+            check_diagram(dir.path(), &urs, &path, &options),
+            UrsStatus::EmptySvg { .. }
+        ));
+
+        options.deep = false;
+        options.check_gzip = true;
+        assert!(matches!(
+            check_diagram(dir.path(), &urs, &path, &options),
+            UrsStatus::EmptySvg { .. }
+        ));
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_best_copy_of_duplicates() -> Result<()> {
+        let base = PathBuf::from("foo");
+        let urs: UrsId = "URS0000000372".parse()?;
+        let paths = vec![
+            PathBuf::from("foo/URS0000000372-E-Ser.colored.svg"),
+            PathBuf::from("foo/URS0000000372.svg"),
+            PathBuf::from("foo/URS/00/00/00/03/URS0000000372.svg.gz"),
+        ];
+        let options = check_options("flat,uncompressed")?;
+        let statuses = check_copies(&base, &urs, paths.clone(), &options);
+        assert_eq!(statuses.len(), 3);
+        assert!(matches!(
+            statuses.last(),
+            Some(UrsStatus::CorrectSvg { .. })
+        ));
+        for status in &statuses[..2] {
+            match status {
+                UrsStatus::DuplicateSvg {
+                    canonical,
+                    locations,
+                    ..
+                } => {
+                    assert_eq!(canonical, &paths[1]);
+                    assert_eq!(locations.len(), 3);
+                }
+                status => panic!("Unexpected status {:?}", status),
+            }
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn finds_duplicates_in_other_directories() -> Result<()> {
+        let dir = TempDir::new("duplicates")?;
+        let required = dir.write("required", "URS0000000372\n")?;
+        dir.write("tree/URS/00/00/00/03/URS0000000372.svg", "")?;
+        let correct = dir.write("tree/URS0000000372.svg", "")?;
+
+        let options = check_options("flat,uncompressed")?;
+        let found = report(&dir.path().join("tree"), &options, required)?;
+        assert_eq!(found.len(), 2);
+        match &found[0] {
+            UrsStatus::DuplicateSvg { canonical, .. } => assert_eq!(canonical, &correct),
+            status => panic!("Unexpected status {:?}", status),
+        }
+        assert!(matches!(found[1], UrsStatus::CorrectSvg { .. }));
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_expected_copy_found_last() -> Result<()> {
+        let dir = TempDir::new("expected-last")?;
+        let required = dir.write("required", "URS0000000372\n")?;
+        let correct = dir.write("tree/URS0000000372.svg", "")?;
+        let sharded = dir.write("tree/URS/00/00/00/03/URS0000000372.svg", "")?;
+        let unknown = dir.write("tree/A/URS0000000372.svg", "")?;
+
+        let options = check_options("flat,uncompressed")?;
+        let found = report(&dir.path().join("tree"), &options, required)?;
+        assert_eq!(found.len(), 3);
+        let expected = vec![unknown.clone(), sharded.clone(), correct.clone()];
+        for (status, copy) in found.iter().zip(&[unknown, sharded]) {
+            match status {
+                UrsStatus::DuplicateSvg {
+                    found_at,
+                    canonical,
+                    locations,
+                    ..
+                } => {
+                    assert_eq!(found_at, copy);
+                    assert_eq!(canonical, &correct);
+                    assert_eq!(locations, &expected);
+                }
+                status => panic!("Unexpected status {:?}", status),
+            }
+        }
+        assert!(matches!(found[2], UrsStatus::CorrectSvg { .. }));
+        return Ok(());
+    }
+
+    #[test]
+    fn skips_excluded_directories() -> Result<()> {
+        let dir = TempDir::new("exclude")?;
+        let required = dir.write("required", "URS0000000372\n")?;
+        dir.write("quarantine/URS0000000372.svg", "")?;
+
+        let mut options = check_options("model-family,uncompressed")?;
+        let found = report(dir.path(), &options, required.clone())?;
+        assert!(found
+            .iter()
+            .any(|s| matches!(s, UrsStatus::CorrectSvg { .. })));
+
+        options.exclude.push(dir.path().join("quarantine"));
+        let found = report(dir.path(), &options, required)?;
+        assert!(found
+            .iter()
+            .any(|s| matches!(s, UrsStatus::MissingSvg { .. })));
+        return Ok(());
+    }
+
+    #[test]
+    fn reports_missing_diagrams_in_order() -> Result<()> {
+        let dir = TempDir::new("order")?;
+        let required = dir.write(
+            "required",
+            "URS00000ABCDE\nURS0000000372\nURS000000FFFF\nURS0000000001\n",
+        )?;
+        let options = check_options("model-family,uncompressed")?;
+        let missing: Vec<String> = report(dir.path(), &options, required)?
+            .iter()
+            .filter_map(|s| match s {
+                UrsStatus::MissingSvg { urs } => Some(urs.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            missing,
+            vec![
+                "URS0000000001",
+                "URS0000000372",
+                "URS000000FFFF",
+                "URS00000ABCDE"
+            ]
+        );
+        return Ok(());
+    }
+}
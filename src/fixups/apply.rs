@@ -0,0 +1,581 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{copy, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Result};
+
+use crate::fixups::{each_status, CheckOptions, UrsStatus};
+use crate::fs::{canonical, write_atomically};
+use crate::layout::Layout;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Action {
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Compress {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Decompress {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Quarantine {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Archive {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Delete a file, this cannot be undone.
+    Prune {
+        from: PathBuf,
+    },
+}
+
+/// What to do with diagrams which are not required.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Extras {
+    Keep,
+    Prune,
+    /// Move them into a tree under the given directory.
+    Archive(PathBuf),
+}
+
+pub struct ApplyOptions {
+    pub dry_run: bool,
+    pub layout: Layout,
+    pub quarantine: Option<PathBuf>,
+    pub journal: Option<PathBuf>,
+    pub extras: Extras,
+}
+
+impl FromStr for Extras {
+    type Err = anyhow::Error;
+
+    /// Parse `keep`, `prune` or `archive:DIR`.
+    fn from_str(raw: &str) -> Result<Self> {
+        return match raw {
+            "keep" => Ok(Self::Keep),
+            "prune" => Ok(Self::Prune),
+            _ => match raw.strip_prefix("archive:") {
+                Some(dir) if !dir.is_empty() => Ok(Self::Archive(PathBuf::from(dir))),
+                _ => Err(anyhow!(
+                    "Unknown extras option '{}', expected keep, prune or archive:DIR",
+                    raw
+                )),
+            },
+        };
+    }
+}
+
+fn is_compressed(path: &Path) -> bool {
+    return path.extension().is_some_and(|e| e == "gz");
+}
+
+impl Action {
+    pub fn from_status(base: &Path, options: &ApplyOptions, status: UrsStatus) -> Option<Self> {
+        let quarantine = &options.quarantine;
+        return match status {
+            UrsStatus::MoveSvg {
+                found_at,
+                expected_path,
+                ..
+            } => Some(Self::Move {
+                from: found_at,
+                to: expected_path,
+            }),
+            UrsStatus::CompressSvg {
+                found_at,
+                expected_path,
+                ..
+            } => Some(Self::Compress {
+                from: found_at,
+                to: expected_path,
+            }),
+            UrsStatus::WrongLayout {
+                found_at,
+                expected_path: Some(expected_path),
+                ..
+            } => Some(
+                match (is_compressed(&found_at), is_compressed(&expected_path)) {
+                    (false, true) => Self::Compress {
+                        from: found_at,
+                        to: expected_path,
+                    },
+                    (true, false) => Self::Decompress {
+                        from: found_at,
+                        to: expected_path,
+                    },
+                    _ => Self::Move {
+                        from: found_at,
+                        to: expected_path,
+                    },
+                },
+            ),
+            UrsStatus::CorruptSvg { found_at, .. }
+            | UrsStatus::EmptySvg { found_at, .. }
+            | UrsStatus::MalformedSvg { found_at, .. }
+            | UrsStatus::DuplicateSvg { found_at, .. } => {
+                Self::quarantine(base, quarantine, found_at)
+            }
+            UrsStatus::UnknownFile { path } => Self::quarantine(base, quarantine, path),
+            UrsStatus::ExtraSvg { found_at, .. } => match &options.extras {
+                Extras::Keep => None,
+                Extras::Prune => Some(Self::Prune { from: found_at }),
+                Extras::Archive(dir) => {
+                    let found_at = resolved(found_at);
+                    match found_at.starts_with(dir) {
+                        true => None,
+                        false => Some(Self::Archive {
+                            to: relocated(base, dir, &found_at),
+                            from: found_at,
+                        }),
+                    }
+                }
+            },
+            _ => None,
+        };
+    }
+
+    fn quarantine(base: &Path, quarantine: &Option<PathBuf>, path: PathBuf) -> Option<Self> {
+        let path = resolved(path);
+        return match quarantine {
+            Some(dir) if path.is_file() && !path.starts_with(dir) => Some(Self::Quarantine {
+                to: relocated(base, dir, &path),
+                from: path,
+            }),
+            _ => None,
+        };
+    }
+
+    pub fn source(&self) -> &PathBuf {
+        return match self {
+            Self::Move { from, .. } => from,
+            Self::Compress { from, .. } => from,
+            Self::Decompress { from, .. } => from,
+            Self::Quarantine { from, .. } => from,
+            Self::Archive { from, .. } => from,
+            Self::Prune { from } => from,
+        };
+    }
+
+    /// The file this action creates, if any.
+    pub fn target(&self) -> Option<&PathBuf> {
+        return match self {
+            Self::Move { to, .. } => Some(to),
+            Self::Compress { to, .. } => Some(to),
+            Self::Decompress { to, .. } => Some(to),
+            Self::Quarantine { to, .. } => Some(to),
+            Self::Archive { to, .. } => Some(to),
+            Self::Prune { .. } => None,
+        };
+    }
+
+    /// The action which will exactly revert this one, if it can be reverted.
+    pub fn inverse(&self) -> Option<Self> {
+        return match self.clone() {
+            Self::Move { from, to } => Some(Self::Move { from: to, to: from }),
+            Self::Compress { from, to } => Some(Self::Decompress { from: to, to: from }),
+            Self::Decompress { from, to } => Some(Self::Compress { from: to, to: from }),
+            Self::Quarantine { from, to } => Some(Self::Quarantine { from: to, to: from }),
+            Self::Archive { from, to } => Some(Self::Archive { from: to, to: from }),
+            Self::Prune { .. } => None,
+        };
+    }
+
+    pub fn perform(&self) -> Result<()> {
+        if let Some(parent) = self.target().and_then(|t| t.parent()) {
+            create_dir_all(parent)?;
+        }
+
+        match self {
+            Self::Move { from, to }
+            | Self::Quarantine { from, to }
+            | Self::Archive { from, to } => rename(from, to)?,
+            Self::Prune { from } => remove_file(from)?,
+            Self::Compress { from, to } => {
+                let mut input = BufReader::new(File::open(from)?);
+                write_atomically(to, |file| {
+                    let mut gz = GzEncoder::new(file, Compression::default());
+                    copy(&mut input, &mut gz)?;
+                    gz.finish()?;
+                    return Ok(());
+                })?;
+                remove_file(from)?;
+            }
+            Self::Decompress { from, to } => {
+                let mut input = GzDecoder::new(BufReader::new(File::open(from)?));
+                write_atomically(to, |file| {
+                    copy(&mut input, file)?;
+                    return Ok(());
+                })?;
+                remove_file(from)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// The canonical form of a path from a status, so it can be compared with the
+/// canonical base and directories `apply` uses.
+fn resolved(path: PathBuf) -> PathBuf {
+    return canonical(&path).unwrap_or(path);
+}
+
+/// The path `path`, relative to `base`, would have under `dir`.
+fn relocated(base: &Path, dir: &Path, path: &Path) -> PathBuf {
+    return dir.join(path.strip_prefix(base).unwrap_or(path));
+}
+
+fn load_journal(path: &PathBuf) -> Result<Vec<Action>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut actions = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        actions.push(serde_json::from_str(&line)?);
+    }
+    return Ok(actions);
+}
+
+fn load_report(path: &PathBuf) -> Result<Vec<UrsStatus>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut statuses = Vec::new();
+    for line in reader.lines() {
+        statuses.push(serde_json::from_str(&line?)?);
+    }
+    return Ok(statuses);
+}
+
+struct Applier<W: Write> {
+    options: ApplyOptions,
+    completed: HashSet<PathBuf>,
+    journal: Option<BufWriter<File>>,
+    /// Where each action is written as a JSON line before it is performed.
+    out: W,
+}
+
+impl<W: Write> Applier<W> {
+    fn new(options: ApplyOptions, out: W) -> Result<Self> {
+        let mut completed = HashSet::new();
+        let mut journal = None;
+        if let Some(path) = &options.journal {
+            if path.exists() {
+                for action in load_journal(path)? {
+                    completed.insert(action.source().clone());
+                }
+                log::info!("Resuming with {} completed actions", completed.len());
+            }
+            if !options.dry_run {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                journal = Some(BufWriter::new(file));
+            }
+        }
+        return Ok(Self {
+            options,
+            completed,
+            journal,
+            out,
+        });
+    }
+
+    /// Perform an action, recording it in the journal first. A crash can
+    /// then only leave a journaled action which was not performed, which is
+    /// performed when resuming and skipped by `undo`.
+    fn handle(&mut self, action: Action) -> Result<()> {
+        let journaled = self.completed.contains(action.source());
+        if journaled && !action.source().exists() {
+            log::debug!("Skipping completed action {:?}", &action);
+            return Ok(());
+        }
+
+        if !action.source().exists() {
+            log::warn!("Skipping {:?}, {:?} is missing", &action, action.source());
+            return Ok(());
+        }
+
+        if action.target().is_some_and(|t| t.exists()) {
+            log::warn!("Not overwriting existing file for {:?}", &action);
+            return Ok(());
+        }
+
+        writeln!(self.out, "{}", serde_json::to_string(&action)?)?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        if let Some(journal) = self.journal.as_mut().filter(|_| !journaled) {
+            writeln!(journal, "{}", serde_json::to_string(&action)?)?;
+            journal.flush()?;
+        }
+        return action.perform();
+    }
+}
+
+/// Perform the action needed to fix each status, either from a saved report
+/// or by checking the tree against a file of required URS ids. Every action
+/// is written to `out` as a JSON line. The base, quarantine and archive
+/// directories are canonicalized first, so files already moved out of the way
+/// are recognized however the directories were given.
+pub fn apply<W: Write>(
+    base: &Path,
+    report: Option<PathBuf>,
+    required: Option<PathBuf>,
+    mut options: ApplyOptions,
+    out: W,
+) -> Result<()> {
+    let base = &canonical(base)?;
+    options.quarantine = options.quarantine.as_deref().map(canonical).transpose()?;
+    if let Extras::Archive(dir) = &options.extras {
+        options.extras = Extras::Archive(canonical(dir)?);
+    }
+
+    let mut exclude: Vec<PathBuf> = options.quarantine.iter().cloned().collect();
+    if let Extras::Archive(dir) = &options.extras {
+        exclude.push(dir.clone());
+    }
+    let check = CheckOptions {
+        layout: options.layout,
+        deep: false,
+        check_gzip: false,
+        exclude,
+    };
+    let mut applier = Applier::new(options, out)?;
+    return match (report, required) {
+        (Some(report), _) => {
+            for status in load_report(&report)? {
+                if let Some(action) = Action::from_status(base, &applier.options, status) {
+                    applier.handle(action)?;
+                }
+            }
+            Ok(())
+        }
+        (None, Some(required)) => each_status(base, &check, required, |status| {
+            return match Action::from_status(base, &applier.options, status) {
+                Some(action) => applier.handle(action),
+                None => Ok(()),
+            };
+        }),
+        (None, None) => Err(anyhow!("Must give either a report or required file")),
+    };
+}
+
+/// The name a journal is moved to once it has been undone.
+fn undone_path(journal: &Path) -> PathBuf {
+    let mut name = journal.as_os_str().to_owned();
+    name.push(".undone");
+    return PathBuf::from(name);
+}
+
+/// Perform the inverse of every action in a journal, latest first. Every
+/// inverse action is written to `out` as a JSON line. The journal is then
+/// moved aside, so applying with it again starts from scratch instead of
+/// treating the undone actions as completed.
+pub fn undo<W: Write>(journal: PathBuf, dry_run: bool, mut out: W) -> Result<()> {
+    let actions = load_journal(&journal)?;
+    for action in actions.iter().rev() {
+        let inverse = match action.inverse() {
+            Some(inverse) => inverse,
+            None => {
+                log::warn!("Cannot undo {:?}", action);
+                continue;
+            }
+        };
+        writeln!(out, "{}", serde_json::to_string(&inverse)?)?;
+        if dry_run {
+            continue;
+        }
+        if !inverse.source().exists() {
+            // Actions are journaled before they are performed, so an
+            // interrupted run may have never performed the last one.
+            match action.source().exists() {
+                true => log::info!("Not undoing {:?}, it was never performed", action),
+                false => log::warn!(
+                    "Cannot undo {:?}, {:?} is missing",
+                    action,
+                    inverse.source()
+                ),
+            }
+            continue;
+        }
+        inverse.perform()?;
+    }
+    if !dry_run {
+        let undone = undone_path(&journal);
+        log::info!("Moving undone journal to {:?}", &undone);
+        rename(&journal, &undone)?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::io;
+
+    #[test]
+    fn inverse_round_trips() {
+        let action = Action::Compress {
+            from: PathBuf::from("a/URS0000000001.svg"),
+            to: PathBuf::from("a/URS0000000001.svg.gz"),
+        };
+        assert_eq!(
+            action.inverse(),
+            Some(Action::Decompress {
+                from: PathBuf::from("a/URS0000000001.svg.gz"),
+                to: PathBuf::from("a/URS0000000001.svg"),
+            })
+        );
+        assert_eq!(action.inverse().and_then(|a| a.inverse()), Some(action));
+    }
+
+    fn options(quarantine: &Path, journal: Option<PathBuf>) -> ApplyOptions {
+        return ApplyOptions {
+            dry_run: false,
+            layout: Layout::default(),
+            quarantine: Some(PathBuf::from(quarantine)),
+            journal,
+            extras: Extras::Keep,
+        };
+    }
+
+    #[test]
+    fn quarantines_relative_to_base() -> Result<()> {
+        let dir = TempDir::new("quarantine")?;
+        let path = dir.write("notes.txt", "")?;
+        let status = UrsStatus::UnknownFile { path: path.clone() };
+        let options = options(&dir.path().join("quarantine"), None);
+        assert_eq!(
+            Action::from_status(dir.path(), &options, status),
+            Some(Action::Quarantine {
+                from: path,
+                to: dir.path().join("quarantine/notes.txt"),
+            })
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn quarantines_once_with_an_absolute_directory() -> Result<()> {
+        let dir = TempDir::within(Path::new("target"), "absolute")?;
+        let base = dir.path().join("tree");
+        dir.write("tree/notes.txt", "")?;
+        let required = dir.write("required", "URS0000000372\n")?;
+        let quarantine = canonical(&base)?.join("quarantine");
+        for _ in 0..2 {
+            let options = options(&quarantine, None);
+            apply(&base, None, Some(required.clone()), options, io::sink())?;
+        }
+        assert!(quarantine.join("notes.txt").exists());
+        assert!(!quarantine.join("quarantine").exists());
+        return Ok(());
+    }
+
+    #[test]
+    fn applies_again_after_undo() -> Result<()> {
+        let dir = TempDir::new("undo")?;
+        let path = dir.write("notes.txt", "")?;
+        let status = UrsStatus::UnknownFile { path: path.clone() };
+        let report = dir.write("report.json", &serde_json::to_string(&status)?)?;
+        let journal = dir.path().join("journal");
+        let quarantine = dir.path().join("quarantine");
+        let quarantined = quarantine.join("notes.txt");
+        let run = || {
+            let options = options(&quarantine, Some(journal.clone()));
+            return apply(dir.path(), Some(report.clone()), None, options, io::sink());
+        };
+
+        run()?;
+        assert!(quarantined.exists() && !path.exists());
+
+        undo(journal.clone(), false, io::sink())?;
+        assert!(path.exists() && !quarantined.exists());
+        assert!(!journal.exists() && undone_path(&journal).exists());
+
+        run()?;
+        assert!(quarantined.exists() && !path.exists());
+        return Ok(());
+    }
+
+    #[test]
+    fn handles_journaled_actions_which_were_not_performed() -> Result<()> {
+        let dir = TempDir::new("interrupted")?;
+        let path = dir.write("notes.txt", "")?;
+        let quarantined = dir.path().join("quarantine/notes.txt");
+        let action = Action::Quarantine {
+            from: path.clone(),
+            to: quarantined.clone(),
+        };
+        let journal = dir.write("journal", &serde_json::to_string(&action)?)?;
+        let report = dir.write(
+            "report.json",
+            &serde_json::to_string(&UrsStatus::UnknownFile { path: path.clone() })?,
+        )?;
+
+        undo(journal.clone(), false, io::sink())?;
+        assert!(path.exists() && !quarantined.exists());
+
+        std::fs::rename(undone_path(&journal), &journal)?;
+        let options = options(&dir.path().join("quarantine"), Some(journal.clone()));
+        apply(dir.path(), Some(report), None, options, io::sink())?;
+        assert!(quarantined.exists() && !path.exists());
+        assert_eq!(load_journal(&journal)?, vec![action]);
+        return Ok(());
+    }
+
+    #[test]
+    fn handles_extra_diagrams() -> Result<()> {
+        let status = || UrsStatus::ExtraSvg {
+            urs: "URS0000000372".parse().unwrap(),
+            found_at: PathBuf::from("tree/URS0000000372.svg"),
+        };
+        let mut options = ApplyOptions {
+            dry_run: true,
+            layout: Layout::default(),
+            quarantine: None,
+            journal: None,
+            extras: "keep".parse()?,
+        };
+        let base = &canonical(Path::new("tree"))?;
+        assert_eq!(Action::from_status(base, &options, status()), None);
+
+        options.extras = "prune".parse()?;
+        let pruned = Action::from_status(base, &options, status());
+        assert_eq!(
+            pruned,
+            Some(Action::Prune {
+                from: PathBuf::from("tree/URS0000000372.svg")
+            })
+        );
+        assert_eq!(pruned.and_then(|a| a.inverse()), None);
+
+        options.extras = "archive:old".parse()?;
+        assert_eq!(
+            Action::from_status(base, &options, status()),
+            Some(Action::Archive {
+                from: base.join("URS0000000372.svg"),
+                to: PathBuf::from("old/URS0000000372.svg"),
+            })
+        );
+        assert!("archive:".parse::<Extras>().is_err());
+        return Ok(());
+    }
+}
@@ -0,0 +1,212 @@
+//! Totals of the statuses in a report, for trees too large to read a line per
+//! file, and the policy used to decide if a report should fail.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use anyhow::{anyhow, Result};
+
+use crate::fixups::{UrsStatus, STATUS_NAMES};
+use crate::layout::Layout;
+
+/// The number of problems found in a single directory.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DirectoryCount {
+    pub directory: PathBuf,
+    pub problems: usize,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub total: usize,
+    pub counts: BTreeMap<String, usize>,
+    /// Counts of each status, grouped by the shard directory, relative to the
+    /// base, the file is in, or should be in.
+    pub by_shard: BTreeMap<String, BTreeMap<String, usize>>,
+    pub top_directories: Vec<DirectoryCount>,
+    #[serde(skip)]
+    problems: HashMap<PathBuf, usize>,
+}
+
+/// Which statuses cause a report to fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailPolicy {
+    Never,
+    /// Fail on any status other than `CorrectSvg`.
+    AnyProblem,
+    Statuses(HashSet<String>),
+}
+
+/// The directory a status is about. Correct and missing diagrams have no
+/// path, so use where the layout places them, ignoring any model.
+fn directory(base: &Path, layout: &Layout, status: &UrsStatus) -> PathBuf {
+    return match status {
+        UrsStatus::CorrectSvg { urs } | UrsStatus::MissingSvg { urs } => {
+            layout.directory_path(base, urs, None)
+        }
+        _ => status
+            .found_at()
+            .and_then(|p| p.parent())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(base)),
+    };
+}
+
+/// The first `depth` components of `path` below `base`.
+fn shard_name(base: &Path, path: &Path, depth: usize) -> String {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    let parts: Vec<String> = relative
+        .components()
+        .take(depth)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    return match parts.is_empty() {
+        true => String::from("."),
+        false => parts.join("/"),
+    };
+}
+
+impl Summary {
+    pub fn record(&mut self, base: &Path, layout: &Layout, status: &UrsStatus, depth: usize) {
+        let name = status.name().to_string();
+        self.total += 1;
+        *self.counts.entry(name.clone()).or_insert(0) += 1;
+
+        let directory = directory(base, layout, status);
+        let shard = shard_name(base, &directory, depth);
+        *self
+            .by_shard
+            .entry(shard)
+            .or_default()
+            .entry(name)
+            .or_insert(0) += 1;
+
+        if status.is_problem() {
+            *self.problems.entry(directory).or_insert(0) += 1;
+        }
+    }
+
+    /// Select the `top` directories with the most problems.
+    pub fn finish(&mut self, top: usize) {
+        let mut directories: Vec<DirectoryCount> = self
+            .problems
+            .drain()
+            .map(|(directory, problems)| DirectoryCount {
+                directory,
+                problems,
+            })
+            .collect();
+        directories.sort_by(|a, b| {
+            b.problems
+                .cmp(&a.problems)
+                .then_with(|| a.directory.cmp(&b.directory))
+        });
+        directories.truncate(top);
+        self.top_directories = directories;
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        return self.counts.get(name).cloned().unwrap_or(0);
+    }
+}
+
+impl FailPolicy {
+    /// Fail if the summary contains any status the policy does not allow.
+    pub fn check(&self, summary: &Summary) -> Result<()> {
+        let failing: Vec<String> = summary
+            .counts
+            .iter()
+            .filter(|(name, _)| match self {
+                Self::Never => false,
+                Self::AnyProblem => *name != "CorrectSvg",
+                Self::Statuses(names) => names.contains(*name),
+            })
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        if failing.is_empty() {
+            return Ok(());
+        }
+        return Err(anyhow!("Report failed with {}", failing.join(", ")));
+    }
+}
+
+impl FromStr for FailPolicy {
+    type Err = anyhow::Error;
+
+    /// Parse `never`, `any` or a comma separated list of status names.
+    fn from_str(raw: &str) -> Result<Self> {
+        return match raw {
+            "never" => Ok(Self::Never),
+            "any" => Ok(Self::AnyProblem),
+            _ => {
+                let mut names = HashSet::new();
+                for name in raw.split(',').map(|n| n.trim()) {
+                    if !STATUS_NAMES.contains(&name) {
+                        return Err(anyhow!(
+                            "Unknown status '{}', expected never, any or some of {}",
+                            name,
+                            STATUS_NAMES.join(", ")
+                        ));
+                    }
+                    names.insert(name.to_string());
+                }
+                Ok(Self::Statuses(names))
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_statuses() -> Result<()> {
+        let base = Path::new("foo");
+        let layout = Layout::default();
+        let statuses = vec![
+            UrsStatus::CorrectSvg {
+                urs: "URS0000000372".parse()?,
+            },
+            UrsStatus::MissingSvg {
+                urs: "URS0000000373".parse()?,
+            },
+            UrsStatus::UnknownFile {
+                path: PathBuf::from("foo/URS/00/00/00/03/notes.txt"),
+            },
+            UrsStatus::UnknownFile {
+                path: PathBuf::from("foo/URS/01/00/00/00/notes.txt"),
+            },
+        ];
+        let mut summary = Summary::default();
+        for status in &statuses {
+            summary.record(base, &layout, status, 2);
+        }
+        summary.finish(1);
+
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.count("UnknownFile"), 2);
+        assert_eq!(summary.by_shard["URS/00"]["MissingSvg"], 1);
+        assert_eq!(summary.by_shard["URS/01"]["UnknownFile"], 1);
+        assert_eq!(
+            summary.top_directories,
+            vec![DirectoryCount {
+                directory: PathBuf::from("foo/URS/00/00/00/03"),
+                problems: 2,
+            }]
+        );
+
+        assert!("never".parse::<FailPolicy>()?.check(&summary).is_ok());
+        assert!("any".parse::<FailPolicy>()?.check(&summary).is_err());
+        assert!("CorruptSvg".parse::<FailPolicy>()?.check(&summary).is_ok());
+        assert!("CorruptSvg,MissingSvg"
+            .parse::<FailPolicy>()?
+            .check(&summary)
+            .is_err());
+        assert!("Broken".parse::<FailPolicy>().is_err());
+        return Ok(());
+    }
+}
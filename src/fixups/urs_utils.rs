@@ -1,48 +1,19 @@
-use std::path::{Path, PathBuf};
-use std::str;
+//! Utilities for working with URS ids and the paths diagrams are stored at.
 
-use anyhow::{anyhow, Result};
+use std::path::Path;
 
 use regex::Regex;
 
-pub fn directory_path(base: &PathBuf, urs: &String) -> PathBuf {
-    let mut path = PathBuf::from(base);
-    path.push("URS");
-    for x in (3..11).step_by(2) {
-        path.push(urs[x..(x + 2)].to_string());
-    }
-    return path;
-}
-
-pub fn uncompressed_path(base: &PathBuf, urs: &String) -> PathBuf {
-    let mut path = directory_path(&base, &urs);
-    path.push(urs);
-    path.set_extension("svg");
-    return path;
-}
-
-pub fn path_for(base: &PathBuf, urs: &String) -> PathBuf {
-    let mut path = PathBuf::from(uncompressed_path(base, urs));
-    path.set_extension("svg.gz");
-    return path;
-}
-
-pub fn incorrect_paths(base: &PathBuf, urs: &String) -> Vec<PathBuf> {
-    let mut paths = Vec::new();
-    let mut standard_path = path_for(base, urs);
-    standard_path.set_extension(".svg.gz");
-    paths.push(standard_path);
-    return paths;
-}
+use crate::urs::UrsId;
 
+/// Check if `urs` is a URS id, with or without a taxid.
 pub fn looks_like_urs(urs: &str) -> bool {
-    lazy_static! {
-        static ref PATTERN: Regex = Regex::new(r"URS[0-9A-F]{10}$").unwrap();
-    }
-    return PATTERN.is_match(urs);
+    return urs.parse::<UrsId>().is_ok();
 }
 
-pub fn filename_urs(urs: &Path) -> Option<String> {
+/// Extract the URS from a diagram filename, ignoring any extensions and model
+/// suffix.
+pub fn filename_urs(urs: &Path) -> Option<UrsId> {
     lazy_static! {
         static ref MODEL_SUFFIX: Regex = Regex::new(r"-.+$").unwrap();
     }
@@ -55,57 +26,62 @@ pub fn filename_urs(urs: &Path) -> Option<String> {
         .map(|s| s.replace(".svg", ""))
         .map(|s| s.replace(".colored", ""))
         .map(|s| MODEL_SUFFIX.replace(&s, "").to_string())
-        .and_then(|s| match looks_like_urs(&s) {
-            true => Some(s.to_string()),
-            false => None,
-        });
+        .and_then(|s| s.parse().ok());
 }
 
-pub fn int_to_urs(index: usize) -> String {
-    return format!("URS{:010X}", index);
-}
-
-pub fn urs_to_index(urs: &String) -> Result<usize> {
-    let minimal = urs.replace("URS", "");
-    let result = usize::from_str_radix(&minimal, 16);
-    match result {
-        Ok(r) => Ok(r),
-        Err(_) => Err(anyhow!("Could not parse {}", urs)),
+/// Extract the name of the model used to draw a diagram from a filename like
+/// `URS0000C2D164-E-Ser.colored.svg`, if there is one.
+pub fn filename_model(path: &Path) -> Option<String> {
+    lazy_static! {
+        static ref MODEL: Regex =
+            Regex::new(r"^URS[0-9A-F]{10}(_\d+)?-(.+?)(\.colored)?(\.\.?svg)?(\.gz)?$").unwrap();
     }
+
+    let name = path.file_name()?.to_str()?;
+    return MODEL
+        .captures(name)
+        .and_then(|c| c.get(2))
+        .map(|m| m.as_str().to_string());
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn urs(raw: &str) -> UrsId {
+        return raw.parse().unwrap();
+    }
+
     #[test]
     fn matches_urs() {
-        assert_eq!(looks_like_urs("URS00000001AAB82D"), false);
-        assert_eq!(looks_like_urs("URS00000001B1"), true);
-        assert_eq!(looks_like_urs("URS0000000362"), true);
+        assert!(!looks_like_urs("URS00000001AAB82D"));
+        assert!(looks_like_urs("URS00000001B1"));
+        assert!(looks_like_urs("URS0000000362"));
+        assert!(looks_like_urs("URS0000000362_9606"));
+        assert!(!looks_like_urs("URS0000000362_"));
     }
 
     #[test]
     fn extracts_urs() {
         assert_eq!(
             filename_urs(Path::new("a/b/URS0000000372..svg.gz")),
-            Some("URS0000000372".to_string())
+            Some(urs("URS0000000372"))
         );
         assert_eq!(
             filename_urs(Path::new("URS0000000372.svg.gz")),
-            Some("URS0000000372".to_string())
+            Some(urs("URS0000000372"))
         );
         assert_eq!(
             filename_urs(Path::new("URS0000000372.svg")),
-            Some("URS0000000372".to_string())
+            Some(urs("URS0000000372"))
         );
         assert_eq!(
             filename_urs(Path::new("URS0000000372")),
-            Some("URS0000000372".to_string())
+            Some(urs("URS0000000372"))
         );
         assert_eq!(
             filename_urs(Path::new("URS000042DD9D.colored.svg")),
-            Some("URS000042DD9D".to_string())
+            Some(urs("URS000042DD9D"))
         );
         assert_eq!(filename_urs(Path::new("URS00000002D191B..svg.gz")), None);
         assert_eq!(filename_urs(Path::new("URS00000002C67ED..svg.gz")), None);
@@ -114,38 +90,28 @@ mod tests {
         assert_eq!(filename_urs(Path::new("URS00000002C67ED")), None);
         assert_eq!(
             filename_urs(Path::new("URS0000C2D164-E-Ser.colored.svg")),
-            Some("URS0000C2D164".to_string())
+            Some(urs("URS0000C2D164"))
         );
-    }
-
-    #[test]
-    fn creates_correct_final_path() {
-        let mut result = PathBuf::from("foo");
-        result.push("URS");
-        result.push("00");
-        result.push("00");
-        result.push("00");
-        result.push("03");
-        result.push("URS0000000372");
-        result.set_extension("svg.gz");
         assert_eq!(
-            path_for(&PathBuf::from("foo"), &"URS0000000372".to_string()),
-            result
+            filename_urs(Path::new("URS0000C2D164_9606-E-Ser.colored.svg")),
+            Some(urs("URS0000C2D164_9606"))
+        );
+        assert_eq!(
+            filename_urs(Path::new("a/URS0000000372_562.svg.gz")),
+            Some(urs("URS0000000372_562"))
         );
     }
 
     #[test]
-    fn correctly_generates_urs() {
-        assert_eq!(int_to_urs(12601134), String::from("URS0000C0472E"));
-        assert_eq!(int_to_urs(1), String::from("URS0000000001"));
-        assert_eq!(int_to_urs(9), String::from("URS0000000009"));
-    }
-
-    #[test]
-    fn correctly_generates_urs_index() -> Result<()> {
-        assert_eq!(urs_to_index(&String::from("URS0000000009"))?, 9);
-        assert_eq!(urs_to_index(&String::from("URS0000C0472E"))?, 12601134);
-        assert_eq!(urs_to_index(&String::from("URS0000000001"))?, 1);
-        return Ok(());
+    fn extracts_model() {
+        assert_eq!(
+            filename_model(Path::new("a/URS0000C2D164-E-Ser.colored.svg")),
+            Some(String::from("E-Ser"))
+        );
+        assert_eq!(
+            filename_model(Path::new("URS0000C2D164_9606-RF00001.svg.gz")),
+            Some(String::from("RF00001"))
+        );
+        assert_eq!(filename_model(Path::new("URS0000C2D164.svg.gz")), None);
     }
 }
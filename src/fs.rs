@@ -1,54 +1,151 @@
+//! Create the directory tree diagrams are stored in, and safely write files
+//! into it.
+
 use std::fs::create_dir_all;
-use std::fs::File;
+use std::fs::{remove_file, rename, File};
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::fixups::urs_utils;
+use crate::layout::Layout;
+use crate::urs::{Urs, UrsId};
 
-fn generate_paths(max_urs: &String, target: &PathBuf) -> Result<Vec<PathBuf>> {
-    let max = urs_utils::urs_to_index(max_urs)?;
-    let paths = (0..max)
-        .step_by(256)
-        .map(|index| urs_utils::int_to_urs(index))
-        .map(|urs| urs_utils::directory_path(&target, &urs))
-        .collect::<Vec<PathBuf>>();
-    return Ok(paths);
+/// All directories needed to store diagrams for every URS up to `max_urs`.
+pub fn generate_paths(max_urs: &Urs, target: &Path, layout: &Layout) -> Vec<PathBuf> {
+    return layout.directories(target, max_urs);
 }
 
-pub fn create_tree(max_urs: &String, base: &PathBuf) -> Result<()> {
-    for path in generate_paths(&max_urs, &base)? {
+pub fn create_tree(max_urs: &Urs, base: &Path, layout: &Layout) -> Result<()> {
+    for path in generate_paths(max_urs, base, layout) {
         create_dir_all(path)?;
     }
     return Ok(());
 }
 
-pub fn paths(urs_filename: PathBuf, base: PathBuf) -> Result<()> {
-    let file = File::open(urs_filename)?;
+/// Write the path of each URS in a file to `out`. Each line may have a model
+/// name after a comma, which is needed for the model family layout.
+pub fn paths<W: Write>(
+    urs_filename: PathBuf,
+    base: PathBuf,
+    layout: &Layout,
+    mut out: W,
+) -> Result<()> {
+    let file = File::open(&urs_filename)?;
     let file = BufReader::new(file);
-    for line in file.lines() {
-        let urs = line?.trim().to_string();
-        let path = urs_utils::path_for(&base, &urs);
+    for (index, line) in file.lines().enumerate() {
+        let line = line?;
+        let mut parts = line.trim().splitn(2, ',');
+        let urs: UrsId = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| anyhow!("Line {} of {:?}: {}", index + 1, urs_filename, e))?;
+        let model = parts.next().map(|m| m.trim());
+        let path = layout.path_for(&base, &urs, model);
         let str_path = path.into_os_string().into_string().unwrap();
-        println!("{}", str_path);
+        writeln!(out, "{}", str_path)?;
     }
     return Ok(());
 }
 
+/// The canonical, absolute, form of `path`, which does not need to exist yet.
+/// The longest part of it which exists is canonicalized and the rest is
+/// appended, so paths can be compared however they were given.
+pub fn canonical(path: &Path) -> Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(mut found) => {
+                found.extend(missing.iter().rev());
+                return Ok(found);
+            }
+            Err(e) => match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = match parent.as_os_str().is_empty() {
+                        true => Path::new("."),
+                        false => parent,
+                    };
+                }
+                _ => return Err(anyhow!("Cannot resolve {:?}: {}", path, e)),
+            },
+        }
+    }
+}
+
+/// Create `path` by writing to a temporary file in the same directory, which
+/// is synced and renamed into place once `write` succeeds. The directory is
+/// then synced so the rename itself survives a crash. A crash can only leave
+/// the temporary file behind, never a partial file at `path`.
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Cannot write to {:?}", path))?;
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+
+    let result = File::create(&temp)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()?;
+            return Ok(());
+        })
+        .and_then(|_| rename(&temp, path).map_err(anyhow::Error::from));
+    if result.is_err() {
+        let _ = remove_file(&temp);
+        return result;
+    }
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()?;
+    return Ok(());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn writes_atomically() -> Result<()> {
+        let dir = TempDir::new("atomic")?;
+        let path = dir.path().join("URS0000000372.svg");
+        write_atomically(&path, |file| {
+            file.write_all(b"<svg/>")?;
+            return Ok(());
+        })?;
+        assert!(write_atomically(&path, |_| Err(anyhow!("Failed"))).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "<svg/>");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        return Ok(());
+    }
 
     #[test]
     fn generates_expected_paths() -> Result<()> {
         assert_eq!(
-            generate_paths(&String::from("URS0000000002"), &PathBuf::from("foo"))?,
+            generate_paths(
+                &"URS0000000002".parse()?,
+                &PathBuf::from("foo"),
+                &Layout::default()
+            ),
             vec![PathBuf::from("foo/URS/00/00/00/00")],
         );
         assert_eq!(
-            generate_paths(&String::from("URS0000000121"), &PathBuf::from("foo"))?,
+            generate_paths(
+                &"URS0000000121".parse()?,
+                &PathBuf::from("foo"),
+                &Layout::default()
+            ),
             vec![
                 PathBuf::from("foo/URS/00/00/00/00"),
                 PathBuf::from("foo/URS/00/00/00/01")
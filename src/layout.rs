@@ -0,0 +1,306 @@
+//! The ways diagrams can be arranged on disk.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::urs::{Urs, UrsId};
+
+/// The deepest sharding possible, one directory per pair of hex digits.
+pub const MAX_DEPTH: usize = 5;
+
+/// The directory used by the model family layout for diagrams without a
+/// known model.
+pub const UNKNOWN_FAMILY: &str = "unknown";
+
+/// How diagrams for taxid suffixed ids are split into directories. Plain URS
+/// ids are always stored by URS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sharding {
+    /// Store all diagrams for a URS in the same directory.
+    Urs,
+    /// Store diagrams in a directory per taxid below the URS directory.
+    UrsTaxid,
+}
+
+/// The directories diagrams are placed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// All diagrams directly in the base directory.
+    Flat,
+    /// Nested directories named by pairs of hex digits of the URS, so a depth
+    /// of 4 stores `URS0000000372` in `URS/00/00/00/03`.
+    Sharded(usize),
+    /// One directory per model used to draw the diagram.
+    ModelFamily,
+}
+
+/// A complete description of where diagrams are stored. This is written, and
+/// parsed, as a comma separated list such as `sharded:3,uncompressed,by-taxid`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub scheme: Scheme,
+    pub compressed: bool,
+    pub sharding: Sharding,
+}
+
+impl FromStr for Sharding {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        return match raw {
+            "urs" => Ok(Self::Urs),
+            "urs-taxid" => Ok(Self::UrsTaxid),
+            _ => Err(anyhow!(
+                "Unknown sharding '{}', expected urs or urs-taxid",
+                raw
+            )),
+        };
+    }
+}
+
+impl Default for Layout {
+    /// The layout used by RNAcentral, `URS/xx/xx/xx/xx/URS….svg.gz`.
+    fn default() -> Self {
+        return Self {
+            scheme: Scheme::Sharded(4),
+            compressed: true,
+            sharding: Sharding::Urs,
+        };
+    }
+}
+
+impl Layout {
+    pub fn extension(&self) -> &'static str {
+        return match self.compressed {
+            true => "svg.gz",
+            false => "svg",
+        };
+    }
+
+    /// The directory the diagram for `id` is stored in. The model is only
+    /// used by the model family layout.
+    pub fn directory_path(&self, base: &Path, id: &UrsId, model: Option<&str>) -> PathBuf {
+        let mut path = PathBuf::from(base);
+        match self.scheme {
+            Scheme::Flat => (),
+            Scheme::Sharded(depth) => {
+                let urs = id.urs().to_string();
+                path.push("URS");
+                for x in (3..).step_by(2).take(depth) {
+                    path.push(&urs[x..(x + 2)]);
+                }
+            }
+            Scheme::ModelFamily => path.push(model.unwrap_or(UNKNOWN_FAMILY)),
+        }
+        if let (Sharding::UrsTaxid, Some(taxid)) = (self.sharding, id.taxid()) {
+            path.push(taxid.to_string());
+        }
+        return path;
+    }
+
+    /// The final path of the diagram for `id`.
+    pub fn path_for(&self, base: &Path, id: &UrsId, model: Option<&str>) -> PathBuf {
+        let mut path = self.directory_path(base, id, model);
+        path.push(format!("{}.{}", id, self.extension()));
+        return path;
+    }
+
+    /// Paths older versions of the pipeline wrote diagrams to by mistake.
+    pub fn incorrect_paths(&self, base: &Path, id: &UrsId, model: Option<&str>) -> Vec<PathBuf> {
+        let mut double_dot = self.directory_path(base, id, model);
+        double_dot.push(format!("{}..{}", id, self.extension()));
+        return vec![double_dot];
+    }
+
+    /// The same layout, but with uncompressed diagrams.
+    pub fn uncompressed(&self) -> Self {
+        return Self {
+            compressed: false,
+            ..*self
+        };
+    }
+
+    /// All directories needed to store diagrams for every URS before
+    /// `max_urs`. Layouts which do not depend on the URS only need `base`.
+    pub fn directories(&self, base: &Path, max_urs: &Urs) -> Vec<PathBuf> {
+        return match self.scheme {
+            Scheme::Flat | Scheme::ModelFamily => vec![PathBuf::from(base)],
+            Scheme::Sharded(depth) => {
+                let step = 16_usize.pow(2 * (MAX_DEPTH - depth) as u32);
+                Urs::range(Urs::default(), *max_urs)
+                    .step_by(step)
+                    .map(|urs| self.directory_path(base, &urs.into(), None))
+                    .collect()
+            }
+        };
+    }
+
+    /// Every other supported layout, used to detect diagrams stored in the
+    /// wrong one.
+    pub fn alternatives(&self) -> Vec<Self> {
+        let mut schemes = vec![Scheme::Flat, Scheme::ModelFamily];
+        schemes.extend((1..=MAX_DEPTH).map(Scheme::Sharded));
+
+        let mut layouts = Vec::new();
+        for scheme in schemes {
+            for compressed in &[true, false] {
+                for sharding in &[Sharding::Urs, Sharding::UrsTaxid] {
+                    let layout = Self {
+                        scheme,
+                        compressed: *compressed,
+                        sharding: *sharding,
+                    };
+                    if layout != *self {
+                        layouts.push(layout);
+                    }
+                }
+            }
+        }
+        return layouts;
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let mut layout = Self::default();
+        for part in raw.split(',').map(|p| p.trim()) {
+            match part {
+                "flat" => layout.scheme = Scheme::Flat,
+                "model-family" => layout.scheme = Scheme::ModelFamily,
+                "sharded" => layout.scheme = Scheme::Sharded(4),
+                "compressed" => layout.compressed = true,
+                "uncompressed" => layout.compressed = false,
+                "by-taxid" => layout.sharding = Sharding::UrsTaxid,
+                _ => {
+                    let depth = part
+                        .strip_prefix("sharded:")
+                        .and_then(|d| d.parse::<usize>().ok())
+                        .filter(|d| (1..=MAX_DEPTH).contains(d))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unknown layout option '{}', expected one of flat, model-family, \
+                                 sharded:1-{}, compressed, uncompressed or by-taxid",
+                                part,
+                                MAX_DEPTH
+                            )
+                        })?;
+                    layout.scheme = Scheme::Sharded(depth);
+                }
+            }
+        }
+        return Ok(layout);
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.scheme {
+            Scheme::Flat => write!(f, "flat")?,
+            Scheme::Sharded(depth) => write!(f, "sharded:{}", depth)?,
+            Scheme::ModelFamily => write!(f, "model-family")?,
+        }
+        if !self.compressed {
+            write!(f, ",uncompressed")?;
+        }
+        if self.sharding == Sharding::UrsTaxid {
+            write!(f, ",by-taxid")?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(raw: &str) -> UrsId {
+        return raw.parse().unwrap();
+    }
+
+    #[test]
+    fn creates_correct_final_path() {
+        assert_eq!(
+            Layout::default().path_for(Path::new("foo"), &id("URS0000000372"), None),
+            PathBuf::from("foo/URS/00/00/00/03/URS0000000372.svg.gz")
+        );
+    }
+
+    #[test]
+    fn generates_double_dot_path() {
+        assert_eq!(
+            Layout::default().incorrect_paths(Path::new("foo"), &id("URS0000000372"), None),
+            vec![PathBuf::from("foo/URS/00/00/00/03/URS0000000372..svg.gz")]
+        );
+    }
+
+    #[test]
+    fn shards_taxid_ids() {
+        let base = Path::new("foo");
+        let by_taxid: Layout = "by-taxid".parse().unwrap();
+        assert_eq!(
+            Layout::default().path_for(base, &id("URS0000000372_9606"), None),
+            PathBuf::from("foo/URS/00/00/00/03/URS0000000372_9606.svg.gz")
+        );
+        assert_eq!(
+            by_taxid.path_for(base, &id("URS0000000372_9606"), None),
+            PathBuf::from("foo/URS/00/00/00/03/9606/URS0000000372_9606.svg.gz")
+        );
+        assert_eq!(
+            by_taxid.path_for(base, &id("URS0000000372"), None),
+            PathBuf::from("foo/URS/00/00/00/03/URS0000000372.svg.gz")
+        );
+    }
+
+    #[test]
+    fn builds_other_layouts() -> Result<()> {
+        let base = Path::new("foo");
+        let urs = id("URS0000000372");
+        let cases = vec![
+            ("flat", "foo/URS0000000372.svg.gz"),
+            ("sharded:2,uncompressed", "foo/URS/00/00/URS0000000372.svg"),
+            ("sharded:5", "foo/URS/00/00/00/03/72/URS0000000372.svg.gz"),
+            ("model-family", "foo/RF00001/URS0000000372.svg.gz"),
+        ];
+        for (raw, expected) in cases {
+            let layout: Layout = raw.parse()?;
+            assert_eq!(layout.to_string(), raw);
+            assert_eq!(
+                layout.path_for(base, &urs, Some("RF00001")),
+                PathBuf::from(expected)
+            );
+        }
+        assert!("sharded:6".parse::<Layout>().is_err());
+        assert!("nested".parse::<Layout>().is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn generates_expected_directories() -> Result<()> {
+        let base = Path::new("foo");
+        assert_eq!(
+            Layout::default().directories(base, &"URS0000000121".parse()?),
+            vec![
+                PathBuf::from("foo/URS/00/00/00/00"),
+                PathBuf::from("foo/URS/00/00/00/01")
+            ],
+        );
+        let shallow: Layout = "sharded:3".parse()?;
+        assert_eq!(
+            shallow.directories(base, &"URS0000010001".parse()?),
+            vec![
+                PathBuf::from("foo/URS/00/00/00"),
+                PathBuf::from("foo/URS/00/00/01")
+            ],
+        );
+        assert_eq!(
+            Layout::default().alternatives().len(),
+            (2 + MAX_DEPTH) * 4 - 1
+        );
+        return Ok(());
+    }
+}
@@ -1,8 +1,11 @@
+//! Find the lowest common ancestor of sequences and the models used to draw
+//! them.
+
 use std::collections::HashMap;
 use std::fs::File;
-use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::iter::FromIterator;
 use std::path::PathBuf;
 
 use anyhow::{anyhow, Result};
@@ -10,79 +13,234 @@ use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::lineage;
+use crate::urs::UrsId;
+
+/// The model used to draw the diagram of a sequence.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiagramAssignment {
+    pub urs: UrsId,
+    pub model_name: String,
+    pub sequence_taxid: usize,
+    pub model_taxid: usize,
+}
 
-#[derive(Debug, Deserialize)]
-struct DiagramAssignment {
-    urs: String,
-    model_name: String,
-    sequence_taxid: usize,
-    model_taxid: usize,
+/// If a common ancestor could be found.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LcaStatus {
+    Found,
+    /// The taxid of the sequence, or model, has been deleted so it has no
+    /// lineage.
+    DeletedTaxid,
 }
 
+/// The lowest common ancestor of a sequence and the model used to draw it.
+/// The ancestor is empty if either taxid has been deleted.
 #[derive(Debug, Deserialize, Serialize)]
-struct Lca {
-    urs: String,
+pub struct Lca {
+    pub urs: UrsId,
+    pub taxid: usize,
+    pub model_name: String,
+    pub ancestor_rank: Option<lineage::Rank>,
+    pub ancestor_taxid: Option<usize>,
+    pub ancestor_name: Option<String>,
+    pub rank_distance: Option<usize>,
+    pub status: LcaStatus,
+}
+
+/// The common ancestor of two taxa and the number of ranked taxa passed
+/// through when going from one taxon, up to the ancestor, and down to the
+/// other taxon.
+#[derive(Debug, PartialEq)]
+struct Ancestor {
     taxid: usize,
-    model_name: String,
-    ancestor_rank: lineage::Rank,
+    name: String,
+    rank: lineage::Rank,
+    distance: usize,
 }
 
-type TreeInfo = HashMap<usize, lineage::Mapping>;
+/// All known lineages, along with the taxids which have been merged into
+/// another taxid.
+#[derive(Default)]
+pub struct TreeInfo {
+    mappings: HashMap<usize, lineage::Mapping>,
+    merged: HashMap<usize, usize>,
+}
 
-fn load_taxid_trees(filename: PathBuf) -> Result<TreeInfo> {
-    let file = File::open(filename)?;
-    let file = BufReader::new(file);
+impl TreeInfo {
+    /// Load a file of JSON encoded `lineage::Mapping`, one per line, as
+    /// produced by the `lineage` command.
+    pub fn load(filename: PathBuf) -> Result<Self> {
+        let file = File::open(filename)?;
+        let file = BufReader::new(file);
+
+        let mut info = Self::default();
+        for line in file.lines() {
+            let line = line?;
+            info.insert(serde_json::from_str(&line)?);
+        }
+        return Ok(info);
+    }
+
+    pub fn insert(&mut self, mapping: lineage::Mapping) {
+        let current = mapping.current_taxid();
+        if current != mapping.taxid {
+            self.merged.insert(mapping.taxid, current);
+        }
+        self.mappings.entry(current).or_insert(mapping);
+    }
+
+    /// The taxid which `taxid` has been merged into, or itself if it has not
+    /// been merged.
+    pub fn current_taxid(&self, taxid: usize) -> usize {
+        return *self.merged.get(&taxid).unwrap_or(&taxid);
+    }
+
+    pub fn get(&self, taxid: usize) -> Option<&lineage::Mapping> {
+        return self.mappings.get(&self.current_taxid(taxid));
+    }
+}
+
+impl FromIterator<lineage::Mapping> for TreeInfo {
+    fn from_iter<I: IntoIterator<Item = lineage::Mapping>>(iter: I) -> Self {
+        let mut info = Self::default();
+        for mapping in iter {
+            info.insert(mapping);
+        }
+        return info;
+    }
+}
 
-    let mut info: TreeInfo = HashMap::new();
-    for line in file.lines() {
-        let line = line?;
-        let mapping: lineage::Mapping = serde_json::from_str(&line)?;
-        info.insert(mapping.taxid, mapping);
+fn ranked_steps(lineage: &[lineage::LineageTaxon], index: usize) -> usize {
+    return lineage[1..=index]
+        .iter()
+        .filter_map(|t| t.rank.as_ref().and_then(lineage::Rank::from_string))
+        .filter(|r| r.is_major())
+        .count();
+}
+
+/// The rank of a taxon in a lineage, unranked taxa are treated as clades.
+fn taxon_rank(taxon: &lineage::LineageTaxon) -> lineage::Rank {
+    return taxon
+        .rank
+        .as_ref()
+        .and_then(lineage::Rank::from_string)
+        .unwrap_or(lineage::Rank::Clade);
+}
+
+/// Find the first taxon in the lineage of the sequence which is also in the
+/// lineage of the model. An ancestor without a rank is reported as a clade.
+fn lineage_ancestor(sequence: &lineage::Mapping, model: &lineage::Mapping) -> Option<Ancestor> {
+    let model_positions: HashMap<usize, usize> = model
+        .lineage
+        .iter()
+        .enumerate()
+        .map(|(index, taxon)| (taxon.taxid, index))
+        .collect();
+
+    for (index, taxon) in sequence.lineage.iter().enumerate() {
+        if let Some(model_index) = model_positions.get(&taxon.taxid) {
+            return Some(Ancestor {
+                taxid: taxon.taxid,
+                name: taxon.name.clone(),
+                rank: taxon_rank(taxon),
+                distance: ranked_steps(&sequence.lineage, index)
+                    + ranked_steps(&model.lineage, *model_index),
+            });
+        }
     }
-    return Ok(info);
+    return None;
 }
 
-fn lca(trees: &TreeInfo, assignment: DiagramAssignment) -> Result<Lca> {
-    let sequence_lineage = match trees.get(&assignment.sequence_taxid) {
+fn rank_ancestor(sequence: &lineage::Mapping, model: &lineage::Mapping) -> Option<Ancestor> {
+    for (index, rank) in lineage::Rank::ascending().into_iter().enumerate() {
+        let taxon = match sequence.taxon_at(&rank) {
+            Some(t) => t,
+            None => continue,
+        };
+        if Some(taxon) == model.taxon_at(&rank) {
+            return Some(Ancestor {
+                taxid: taxon.taxid,
+                name: taxon.name.clone(),
+                rank,
+                distance: 2 * index,
+            });
+        }
+    }
+    return None;
+}
+
+fn common_ancestor(sequence: &lineage::Mapping, model: &lineage::Mapping) -> Option<Ancestor> {
+    if sequence.current_taxid() == model.current_taxid() {
+        // The lineage starts at the taxon itself. Mappings without a lineage
+        // do not record its rank, so assume a species.
+        let rank = sequence
+            .lineage
+            .first()
+            .map(taxon_rank)
+            .unwrap_or(lineage::Rank::Species);
+        return Some(Ancestor {
+            taxid: sequence.current_taxid(),
+            name: sequence.name.clone().unwrap_or_default(),
+            rank,
+            distance: 0,
+        });
+    }
+    return lineage_ancestor(sequence, model).or_else(|| rank_ancestor(sequence, model));
+}
+
+/// Find the lowest common ancestor of the sequence and model in `assignment`.
+/// Fails if the lineage of either taxid is not known, a deleted taxid produces
+/// an `Lca` without an ancestor.
+pub fn lca(trees: &TreeInfo, assignment: DiagramAssignment) -> Result<Lca> {
+    let sequence_lineage = match trees.get(assignment.sequence_taxid) {
         Some(v) => Ok(v),
         None => Err(anyhow!(
             "Missing lineage for {}",
             &assignment.sequence_taxid
         )),
     }?;
-    let model_lineage = match trees.get(&assignment.model_taxid) {
+    let model_lineage = match trees.get(assignment.model_taxid) {
         Some(v) => Ok(v),
         None => Err(anyhow!("Missing lineage for {}", &assignment.model_taxid)),
     }?;
 
-    if assignment.sequence_taxid == assignment.model_taxid {
+    if sequence_lineage.deleted || model_lineage.deleted {
         return Ok(Lca {
             urs: assignment.urs,
             taxid: assignment.sequence_taxid,
             model_name: assignment.model_name,
-            ancestor_rank: lineage::Rank::Species,
+            ancestor_rank: None,
+            ancestor_taxid: None,
+            ancestor_name: None,
+            rank_distance: None,
+            status: LcaStatus::DeletedTaxid,
         });
     }
 
-    for rank in lineage::Rank::ascending() {
-        let sequence_taxon = sequence_lineage.taxon_at(&rank);
-        let model_taxon = model_lineage.taxon_at(&rank);
-        if sequence_taxon == model_taxon {
-            return Ok(Lca {
-                urs: assignment.urs,
-                taxid: assignment.sequence_taxid,
-                model_name: assignment.model_name,
-                ancestor_rank: rank,
-            });
-        }
-    }
-
-    return Err(anyhow!("Failed to find lca for {:?}", assignment));
+    return match common_ancestor(sequence_lineage, model_lineage) {
+        Some(ancestor) => Ok(Lca {
+            urs: assignment.urs,
+            taxid: assignment.sequence_taxid,
+            model_name: assignment.model_name,
+            ancestor_rank: Some(ancestor.rank),
+            ancestor_taxid: Some(ancestor.taxid),
+            ancestor_name: Some(ancestor.name),
+            rank_distance: Some(ancestor.distance),
+            status: LcaStatus::Found,
+        }),
+        None => Err(anyhow!("Failed to find lca for {:?}", assignment)),
+    };
 }
 
-pub fn write_lca(taxid_filename: PathBuf, assignments_filename: PathBuf) -> Result<()> {
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    let trees = load_taxid_trees(taxid_filename)?;
+/// Write the lca of every assignment in a CSV file to `out` as CSV.
+pub fn write_lca<W: Write>(
+    taxid_filename: PathBuf,
+    assignments_filename: PathBuf,
+    out: W,
+) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(out);
+    let trees = TreeInfo::load(taxid_filename)?;
     let file = File::open(assignments_filename)?;
     let file = BufReader::new(file);
     let mut reader = csv::Reader::from_reader(file);
@@ -95,3 +253,108 @@ pub fn write_lca(taxid_filename: PathBuf, assignments_filename: PathBuf) -> Resu
 
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(taxid: usize, lineage: &[(usize, &str, Option<&str>)]) -> lineage::Mapping {
+        let mut mapping = lineage::Mapping::empty(taxid);
+        mapping.lineage = lineage
+            .iter()
+            .map(|(taxid, name, rank)| lineage::LineageTaxon {
+                name: name.to_string(),
+                taxid: *taxid,
+                rank: rank.map(|r| r.to_string()),
+            })
+            .collect();
+        return mapping;
+    }
+
+    #[test]
+    fn finds_unranked_common_ancestor() {
+        let human = mapping(
+            9606,
+            &[
+                (9606, "Homo sapiens", Some("species")),
+                (9605, "Homo", Some("genus")),
+                (9604, "Hominidae", Some("family")),
+                (314146, "Euarchontoglires", None),
+                (2759, "Eukaryota", Some("superkingdom")),
+            ],
+        );
+        let mouse = mapping(
+            10090,
+            &[
+                (10090, "Mus musculus", Some("species")),
+                (10088, "Mus", Some("genus")),
+                (10066, "Muridae", Some("family")),
+                (314146, "Euarchontoglires", None),
+                (2759, "Eukaryota", Some("superkingdom")),
+            ],
+        );
+        assert_eq!(
+            common_ancestor(&human, &mouse),
+            Some(Ancestor {
+                taxid: 314146,
+                name: String::from("Euarchontoglires"),
+                rank: lineage::Rank::Clade,
+                distance: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn reports_deleted_taxids() -> Result<()> {
+        let human = mapping(9606, &[(9606, "Homo sapiens", Some("species"))]);
+        let trees: TreeInfo = vec![human, lineage::Mapping::deleted(12)]
+            .into_iter()
+            .collect();
+        let assignment = DiagramAssignment {
+            urs: "URS0000000372".parse()?,
+            model_name: String::from("RF00001"),
+            sequence_taxid: 12,
+            model_taxid: 9606,
+        };
+        let found = lca(&trees, assignment)?;
+        assert_eq!(found.status, LcaStatus::DeletedTaxid);
+        assert_eq!(found.ancestor_taxid, None);
+
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.serialize(found)?;
+        let written = String::from_utf8(wtr.into_inner()?)?;
+        assert_eq!(
+            written.lines().next(),
+            Some("urs,taxid,model_name,ancestor_rank,ancestor_taxid,ancestor_name,rank_distance,status")
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn uses_rank_of_identical_taxa() {
+        let subspecies = mapping(
+            63221,
+            &[
+                (63221, "Homo sapiens neanderthalensis", Some("subspecies")),
+                (9606, "Homo sapiens", Some("species")),
+            ],
+        );
+        let found = common_ancestor(&subspecies, &subspecies).unwrap();
+        assert_eq!(found.rank, lineage::Rank::Subspecies);
+        assert_eq!(found.distance, 0);
+
+        let unranked = mapping(
+            2665952,
+            &[
+                (2665952, "environmental samples", None),
+                (9606, "Homo sapiens", Some("species")),
+            ],
+        );
+        let found = common_ancestor(&unranked, &unranked).unwrap();
+        assert_eq!(found.rank, lineage::Rank::Clade);
+
+        let unknown = lineage::Mapping::empty(9606);
+        let found = common_ancestor(&unknown, &unknown).unwrap();
+        assert_eq!(found.rank, lineage::Rank::Species);
+    }
+}
@@ -0,0 +1,27 @@
+//! Utilities for working with the diagrams R2DT produces: counting the colors
+//! of nucleotides, placing SVGs into the final tree, finding and fixing
+//! problems with that tree, checking the quality of diagrams, and comparing
+//! the taxonomy of sequences with the templates used to draw them.
+// Functions end with an explicit `return`, as everywhere in this crate.
+#![allow(clippy::needless_return)]
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod coloring;
+pub mod ena;
+pub mod fixups;
+pub mod fs;
+pub mod layout;
+pub mod lca;
+pub mod lineage;
+pub mod manifest;
+pub mod migrate;
+pub mod ncbi;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod qa;
+pub mod results;
+#[cfg(test)]
+mod test_utils;
+pub mod urs;
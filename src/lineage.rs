@@ -1,12 +1,14 @@
+//! Build the lineage of taxids, using either ENA or an NCBI taxdump.
+
 extern crate serde;
 extern crate serde_json;
 extern crate serde_xml_rs;
 
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::iter::FromIterator;
 use std::option::Option;
 use std::path::PathBuf;
 use std::{thread, time};
@@ -17,37 +19,139 @@ use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
 
-use crate::ena::{species, EnaTaxonInfo};
+use crate::ena::cache::TaxonomyCache;
+use crate::ena::{lookup, EnaTaxonInfo};
+use crate::ncbi::Taxdump;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub enum Rank {
+    Isolate,
+    Strain,
+    Genotype,
+    Biotype,
+    Morph,
+    Pathogroup,
+    Serotype,
+    Serogroup,
+    FormaSpecialis,
+    Forma,
+    Subvariety,
+    Varietas,
+    Subspecies,
     Species,
+    SpeciesSubgroup,
+    SpeciesGroup,
+    Subseries,
+    Series,
+    Subsection,
+    Section,
+    Subgenus,
     Genus,
+    Subtribe,
+    Tribe,
+    Subfamily,
     Family,
+    Superfamily,
+    Parvorder,
+    Infraorder,
+    Suborder,
     Order,
+    Superorder,
+    Subcohort,
+    Cohort,
+    Infraclass,
+    Subclass,
     Class,
+    Superclass,
+    Subphylum,
     Phylum,
+    Superphylum,
+    Subkingdom,
     Kingdom,
     Superkingdom,
+    Domain,
+    Realm,
     Root,
+    /// An unranked grouping, which has no fixed position relative to other
+    /// ranks.
+    Clade,
 }
 
+/// All ranks with a fixed position, from the lowest to the highest, along with
+/// the name NCBI and ENA use for them.
+const ORDERED_RANKS: [(Rank, &str); 47] = [
+    (Rank::Isolate, "isolate"),
+    (Rank::Strain, "strain"),
+    (Rank::Genotype, "genotype"),
+    (Rank::Biotype, "biotype"),
+    (Rank::Morph, "morph"),
+    (Rank::Pathogroup, "pathogroup"),
+    (Rank::Serotype, "serotype"),
+    (Rank::Serogroup, "serogroup"),
+    (Rank::FormaSpecialis, "forma specialis"),
+    (Rank::Forma, "forma"),
+    (Rank::Subvariety, "subvariety"),
+    (Rank::Varietas, "varietas"),
+    (Rank::Subspecies, "subspecies"),
+    (Rank::Species, "species"),
+    (Rank::SpeciesSubgroup, "species subgroup"),
+    (Rank::SpeciesGroup, "species group"),
+    (Rank::Subseries, "subseries"),
+    (Rank::Series, "series"),
+    (Rank::Subsection, "subsection"),
+    (Rank::Section, "section"),
+    (Rank::Subgenus, "subgenus"),
+    (Rank::Genus, "genus"),
+    (Rank::Subtribe, "subtribe"),
+    (Rank::Tribe, "tribe"),
+    (Rank::Subfamily, "subfamily"),
+    (Rank::Family, "family"),
+    (Rank::Superfamily, "superfamily"),
+    (Rank::Parvorder, "parvorder"),
+    (Rank::Infraorder, "infraorder"),
+    (Rank::Suborder, "suborder"),
+    (Rank::Order, "order"),
+    (Rank::Superorder, "superorder"),
+    (Rank::Subcohort, "subcohort"),
+    (Rank::Cohort, "cohort"),
+    (Rank::Infraclass, "infraclass"),
+    (Rank::Subclass, "subclass"),
+    (Rank::Class, "class"),
+    (Rank::Superclass, "superclass"),
+    (Rank::Subphylum, "subphylum"),
+    (Rank::Phylum, "phylum"),
+    (Rank::Superphylum, "superphylum"),
+    (Rank::Subkingdom, "subkingdom"),
+    (Rank::Kingdom, "kingdom"),
+    (Rank::Superkingdom, "superkingdom"),
+    (Rank::Domain, "domain"),
+    (Rank::Realm, "realm"),
+    (Rank::Root, "root"),
+];
+
 impl Rank {
     pub fn from_string(rank: &String) -> Option<Self> {
-        return match rank.as_ref() {
-            "species" => Some(Self::Species),
-            "genus" => Some(Self::Genus),
-            "family" => Some(Self::Family),
-            "order" => Some(Self::Order),
-            "class" => Some(Self::Class),
-            "phylum" => Some(Self::Phylum),
-            "kingdom" => Some(Self::Kingdom),
-            "superkingdom" => Some(Self::Superkingdom),
-            "root" => Some(Self::Root),
-            _ => None,
-        };
+        if rank == "clade" {
+            return Some(Self::Clade);
+        }
+        return ORDERED_RANKS
+            .iter()
+            .find(|(_, name)| name == rank)
+            .map(|(r, _)| r.clone());
     }
 
+    /// The position of this rank, with sub- and infra- ranks placed between
+    /// the major ranks they belong to. Clades have no position.
+    pub fn level(&self) -> Option<usize> {
+        return ORDERED_RANKS.iter().position(|(r, _)| r == self);
+    }
+
+    /// The ranks which have a dedicated field in a `Mapping`.
+    pub fn is_major(&self) -> bool {
+        return Self::ascending().contains(self);
+    }
+
+    /// The major ranks, from lowest to highest.
     pub fn ascending() -> Vec<Self> {
         return vec![
             Rank::Species,
@@ -63,6 +167,16 @@ impl Rank {
     }
 }
 
+impl PartialOrd for Rank {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return match (self.level(), other.level()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ if self == other => Some(Ordering::Equal),
+            _ => None,
+        };
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct TaxonInfo {
     pub name: String,
@@ -70,6 +184,15 @@ pub struct TaxonInfo {
     pub rank: Rank,
 }
 
+/// A single node of a full lineage, which may not have a rank, such as a clade.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct LineageTaxon {
+    pub name: String,
+    pub taxid: usize,
+    pub rank: Option<String>,
+}
+
+/// The lineage of a single taxid.
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct Mapping {
     pub taxid: usize,
@@ -83,9 +206,53 @@ pub struct Mapping {
     pub kingdom: Option<TaxonInfo>,
     pub superkingdom: Option<TaxonInfo>,
     pub root: Option<TaxonInfo>,
+    /// Taxa at all ranks without a dedicated field, such as subspecies or
+    /// tribe, from lowest to highest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_ranks: Vec<TaxonInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merged_into: Option<usize>,
+    /// The complete lineage, starting at this taxon and ending at the root.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lineage: Vec<LineageTaxon>,
+    /// Set if the taxid has been deleted, in which case there is no lineage.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
 }
 
 impl Mapping {
+    pub fn empty(taxid: usize) -> Self {
+        return Self {
+            taxid,
+            name: None,
+            species: None,
+            genus: None,
+            family: None,
+            order: None,
+            class: None,
+            phylum: None,
+            kingdom: None,
+            superkingdom: None,
+            root: None,
+            other_ranks: Vec::new(),
+            merged_into: None,
+            lineage: Vec::new(),
+            deleted: false,
+        };
+    }
+
+    pub fn deleted(taxid: usize) -> Self {
+        return Self {
+            deleted: true,
+            ..Self::empty(taxid)
+        };
+    }
+
+    /// The taxid this mapping currently describes, following any merge.
+    pub fn current_taxid(&self) -> usize {
+        return self.merged_into.unwrap_or(self.taxid);
+    }
+
     pub fn taxon_at(&self, rank: &Rank) -> Option<&TaxonInfo> {
         match rank {
             Rank::Species => self.species.as_ref(),
@@ -97,9 +264,20 @@ impl Mapping {
             Rank::Kingdom => self.kingdom.as_ref(),
             Rank::Superkingdom => self.superkingdom.as_ref(),
             Rank::Root => self.root.as_ref(),
+            _ => self.other_ranks.iter().find(|t| &t.rank == rank),
         }
     }
 
+    /// Remove everything except the major ranks, which produces the same
+    /// output as older versions.
+    pub fn only_major_ranks(self) -> Self {
+        return Self {
+            other_ranks: Vec::new(),
+            lineage: Vec::new(),
+            ..self
+        };
+    }
+
     pub fn is_empty(&self) -> bool {
         if self.taxid == 131567 {
             return false;
@@ -115,14 +293,75 @@ impl Mapping {
     }
 }
 
+/// Where taxonomy information is loaded from.
+pub enum TaxonomySource {
+    Ena,
+    Cached(TaxonomyCache),
+    Taxdump(Taxdump),
+}
+
+/// The result of looking up a set of taxids. Taxa are keyed by the requested
+/// taxid, so a merged taxid maps to the info of the taxon it was merged into.
+/// Only taxids the source lists as deleted are deleted, any others it has no
+/// record of, such as typos, are unknown.
+pub struct Lookup {
+    pub found: HashMap<usize, EnaTaxonInfo>,
+    pub deleted: HashSet<usize>,
+    pub unknown: HashSet<usize>,
+    pub fetched: bool,
+}
+
+impl TaxonomySource {
+    /// Find the taxonomy information for all taxids.
+    pub fn lookup(&mut self, taxids: &[usize]) -> Result<Lookup> {
+        return match self {
+            Self::Ena => {
+                let (found, unknown) = lookup(taxids)?;
+                Ok(Lookup {
+                    found,
+                    deleted: HashSet::new(),
+                    unknown,
+                    fetched: true,
+                })
+            }
+            Self::Cached(cache) => {
+                let (found, unknown, fetched) = cache.lookup(taxids)?;
+                Ok(Lookup {
+                    found,
+                    deleted: HashSet::new(),
+                    unknown,
+                    fetched,
+                })
+            }
+            Self::Taxdump(taxdump) => {
+                let found = taxdump.lookup(taxids);
+                let (deleted, unknown) = taxids
+                    .iter()
+                    .filter(|t| !found.contains_key(t))
+                    .partition(|t| taxdump.is_deleted(**t));
+                Ok(Lookup {
+                    found,
+                    deleted,
+                    unknown,
+                    fetched: false,
+                })
+            }
+        };
+    }
+}
+
 #[derive(Debug)]
 struct Report {
     total: usize,
     mapped: usize,
+    merged: usize,
+    deleted: usize,
+    unknown: usize,
     unmapped: usize,
 }
 
-fn lineage_mapping(taxon: &EnaTaxonInfo) -> Mapping {
+/// Build the mapping for a taxon from the information ENA provides.
+pub fn lineage_mapping(taxon: &EnaTaxonInfo) -> Mapping {
     let mut mapping = Mapping {
         name: Some(taxon.name.clone()),
         taxid: taxon.taxid,
@@ -139,6 +378,14 @@ fn lineage_mapping(taxon: &EnaTaxonInfo) -> Mapping {
             taxid: 131567,
             rank: Rank::Root,
         }),
+        other_ranks: Vec::new(),
+        merged_into: None,
+        deleted: false,
+        lineage: vec![LineageTaxon {
+            name: taxon.name.clone(),
+            taxid: taxon.taxid,
+            rank: taxon.rank.clone(),
+        }],
     };
 
     if taxon.rank == Some(String::from("species")) {
@@ -150,7 +397,13 @@ fn lineage_mapping(taxon: &EnaTaxonInfo) -> Mapping {
     }
 
     for parent in taxon.parent_taxons() {
-        let rank: Option<Rank> = parent.rank.as_ref().and_then(|r| Rank::from_string(&r));
+        mapping.lineage.push(LineageTaxon {
+            name: parent.name.clone(),
+            taxid: parent.taxid,
+            rank: parent.rank.clone(),
+        });
+
+        let rank: Option<Rank> = parent.rank.as_ref().and_then(Rank::from_string);
 
         if rank.is_none() {
             continue;
@@ -173,59 +426,77 @@ fn lineage_mapping(taxon: &EnaTaxonInfo) -> Mapping {
             Rank::Kingdom => mapping.kingdom = Some(tinfo),
             Rank::Superkingdom => mapping.superkingdom = Some(tinfo),
             Rank::Root => mapping.root = Some(tinfo),
+            _ => mapping.other_ranks.push(tinfo),
         }
     }
 
     return mapping;
 }
 
-fn mappings(taxids: &[usize]) -> Result<Vec<Mapping>> {
-    let species = species(taxids)?;
-    let mut missing: HashSet<usize> = HashSet::from_iter(taxids.iter().cloned());
-    let mut mappings = Vec::new();
-    for entry in species {
-        let info = lineage_mapping(&entry);
-        mappings.push(info);
-        missing.remove(&entry.taxid);
-    }
-
-    let extra = missing.iter().map(|taxid| Mapping {
-        taxid: *taxid,
-        name: None,
-        species: None,
-        genus: None,
-        family: None,
-        order: None,
-        class: None,
-        phylum: None,
-        kingdom: None,
-        superkingdom: None,
-        root: None,
-    });
-
-    mappings.extend(extra);
-    return Ok(mappings);
+/// Build a mapping for each taxid, in the same order. Taxids which could not
+/// be found produce an empty mapping.
+pub fn mappings(lookup: &Lookup, taxids: &[usize]) -> Vec<Mapping> {
+    return taxids
+        .iter()
+        .map(|taxid| match lookup.found.get(taxid) {
+            None => Mapping::empty(*taxid),
+            Some(entry) => {
+                let mut mapping = lineage_mapping(entry);
+                if entry.taxid != *taxid {
+                    mapping.taxid = *taxid;
+                    mapping.merged_into = Some(entry.taxid);
+                }
+                mapping
+            }
+        })
+        .collect();
 }
 
-pub fn write_lineage(chunk_size: usize, filename: PathBuf) -> Result<()> {
+/// Write the mapping of every taxid in a file, one per line, to `out` as JSON
+/// lines. Unknown taxids and those which could not be mapped are skipped, and
+/// deleted taxids are written with only `deleted` set.
+pub fn write_lineage<W: Write>(
+    chunk_size: usize,
+    filename: PathBuf,
+    mut source: TaxonomySource,
+    major_ranks_only: bool,
+    mut out: W,
+) -> Result<()> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
 
     let taxids = reader
         .lines()
-        .into_iter()
         .map(|l| l.unwrap().trim().parse::<usize>().unwrap());
 
     let mut report = Report {
         total: 0,
         mapped: 0,
+        merged: 0,
+        deleted: 0,
+        unknown: 0,
         unmapped: 0,
     };
 
     for chunk in taxids.collect::<Vec<usize>>().chunks(chunk_size) {
         report.total += chunk.len();
-        let mappings = mappings(chunk)?;
-        for mapping in mappings {
+        let lookup = source.lookup(chunk)?;
+        for mapping in mappings(&lookup, chunk) {
+            if lookup.deleted.contains(&mapping.taxid) {
+                report.deleted += 1;
+                warn!("Taxid {} has been deleted", mapping.taxid);
+                let mapping = Mapping::deleted(mapping.taxid);
+                writeln!(out, "{}", serde_json::to_string(&mapping)?)?;
+                continue;
+            }
+            if lookup.unknown.contains(&mapping.taxid) {
+                report.unknown += 1;
+                warn!(
+                    "No mapping found for {}, the taxid is unknown",
+                    mapping.taxid
+                );
+                continue;
+            }
             match mapping.is_empty() {
                 true => {
                     report.unmapped += 1;
@@ -233,13 +504,166 @@ pub fn write_lineage(chunk_size: usize, filename: PathBuf) -> Result<()> {
                 }
                 false => {
                     report.mapped += 1;
-                    println!("{}", serde_json::to_string(&mapping)?);
+                    if let Some(current) = mapping.merged_into {
+                        report.merged += 1;
+                        info!("Taxid {} has been merged into {}", mapping.taxid, current);
+                    }
+                    let mapping = match major_ranks_only {
+                        true => mapping.only_major_ranks(),
+                        false => mapping,
+                    };
+                    writeln!(out, "{}", serde_json::to_string(&mapping)?)?;
                 }
             }
         }
-        let dur = time::Duration::from_millis(200);
-        thread::sleep(dur)
+        if lookup.fetched {
+            let dur = time::Duration::from_millis(200);
+            thread::sleep(dur)
+        }
     }
     info!("Status: {:?}", report);
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ena::cache::CacheMode;
+    use crate::test_utils::TempDir;
+
+    fn human() -> EnaTaxonInfo {
+        return serde_json::from_str(
+            r#"{
+                "scientificName": "Homo sapiens",
+                "taxId": 9606,
+                "rank": "species",
+                "lineage": {"taxon": [
+                    {"scientificName": "Homo", "taxId": 9605, "rank": "genus"},
+                    {"scientificName": "Homininae", "taxId": 207598, "rank": "subfamily"},
+                    {"scientificName": "Hominidae", "taxId": 9604, "rank": "family"},
+                    {"scientificName": "Euarchontoglires", "taxId": 314146, "rank": "superorder"},
+                    {"scientificName": "Eukaryota", "taxId": 2759, "rank": "superkingdom"}
+                ]}
+            }"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn orders_minor_ranks_between_major_ranks() {
+        assert!(Rank::Subspecies < Rank::Species);
+        assert!(Rank::Genus < Rank::Subfamily);
+        assert!(Rank::Subfamily < Rank::Family);
+        assert!(Rank::Infraorder < Rank::Order);
+        assert!(Rank::Superkingdom < Rank::Root);
+        assert_eq!(Rank::Clade.partial_cmp(&Rank::Genus), None);
+        assert_eq!(Rank::Clade.partial_cmp(&Rank::Clade), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn parses_all_rank_names() {
+        for (rank, name) in ORDERED_RANKS.iter() {
+            assert_eq!(Rank::from_string(&name.to_string()), Some(rank.clone()));
+        }
+        assert_eq!(
+            Rank::from_string(&String::from("forma specialis")),
+            Some(Rank::FormaSpecialis)
+        );
+        assert_eq!(Rank::from_string(&String::from("clade")), Some(Rank::Clade));
+        assert_eq!(Rank::from_string(&String::from("no rank")), None);
+    }
+
+    fn lookup_of(found: Vec<(usize, EnaTaxonInfo)>) -> Lookup {
+        return Lookup {
+            found: found.into_iter().collect(),
+            deleted: HashSet::new(),
+            unknown: HashSet::new(),
+            fetched: false,
+        };
+    }
+
+    #[test]
+    fn builds_mappings_without_fetching() {
+        let found = mappings(&lookup_of(vec![(9606, human())]), &[9606, 1]);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].taxid, 9606);
+        assert_eq!(found[0].merged_into, None);
+        assert_eq!(found[0].species.as_ref().map(|t| t.taxid), Some(9606));
+        assert_eq!(found[0].genus.as_ref().map(|t| t.taxid), Some(9605));
+        assert_eq!(found[0].superkingdom.as_ref().map(|t| t.taxid), Some(2759));
+        assert_eq!(found[0].order, None);
+        let lineage: Vec<usize> = found[0].lineage.iter().map(|t| t.taxid).collect();
+        assert_eq!(lineage, vec![9606, 9605, 207598, 9604, 314146, 2759]);
+        assert_eq!(
+            found[0].taxon_at(&Rank::Subfamily).map(|t| t.taxid),
+            Some(207598)
+        );
+        assert_eq!(found[0].other_ranks.len(), 2);
+        assert!(found[1].is_empty());
+    }
+
+    #[test]
+    fn records_merged_taxids() {
+        let found = mappings(&lookup_of(vec![(63221, human())]), &[63221]);
+        assert_eq!(found[0].taxid, 63221);
+        assert_eq!(found[0].merged_into, Some(9606));
+        assert_eq!(found[0].current_taxid(), 9606);
+        assert_eq!(found[0].species.as_ref().map(|t| t.taxid), Some(9606));
+    }
+
+    #[test]
+    fn separates_unknown_and_deleted_taxids() -> Result<()> {
+        let taxdump = Taxdump::from_readers(
+            "1\t|\t1\t|\tno rank\t|\n".as_bytes(),
+            "".as_bytes(),
+            "".as_bytes(),
+            "12\t|\n".as_bytes(),
+        )?;
+        let found = TaxonomySource::Taxdump(taxdump).lookup(&[12, 77])?;
+        assert_eq!(found.deleted, vec![12].into_iter().collect());
+        assert_eq!(found.unknown, vec![77].into_iter().collect());
+
+        let dir = TempDir::new("unknown")?;
+        let path = dir.write(
+            "cache.jsonl",
+            "{\"taxid\":77,\"fetched\":0,\"info\":null}\n",
+        )?;
+        let cache = TaxonomyCache::open(&path, CacheMode::CacheOnly, None)?;
+        let found = TaxonomySource::Cached(cache).lookup(&[77])?;
+        assert!(found.deleted.is_empty());
+        assert_eq!(found.unknown, vec![77].into_iter().collect());
+        return Ok(());
+    }
+
+    #[test]
+    fn deleted_taxids_can_be_loaded() -> Result<()> {
+        let taxdump = Taxdump::from_readers(
+            "1\t|\t1\t|\tno rank\t|\n9606\t|\t1\t|\tspecies\t|\n".as_bytes(),
+            "9606\t|\tHomo sapiens\t|\t\t|\tscientific name\t|\n".as_bytes(),
+            "".as_bytes(),
+            "12\t|\n".as_bytes(),
+        )?;
+        let dir = TempDir::new("lineage")?;
+        let taxids = dir.write("taxids.txt", "9606\n12\n")?;
+        let mut out = Vec::new();
+        write_lineage(
+            10,
+            taxids,
+            TaxonomySource::Taxdump(taxdump),
+            false,
+            &mut out,
+        )?;
+        let lineage = dir.write("lineage.json", std::str::from_utf8(&out)?)?;
+        let trees = crate::lca::TreeInfo::load(lineage)?;
+
+        let assignment = crate::lca::DiagramAssignment {
+            urs: "URS0000000372".parse()?,
+            model_name: String::from("RF00001"),
+            sequence_taxid: 12,
+            model_taxid: 9606,
+        };
+        let found = crate::lca::lca(&trees, assignment)?;
+        assert_eq!(found.status, crate::lca::LcaStatus::DeletedTaxid);
+        return Ok(());
+    }
+}
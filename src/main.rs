@@ -1,28 +1,130 @@
+// Functions end with an explicit `return`, as everywhere in this crate.
+#![allow(clippy::needless_return)]
+
+use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
-#[macro_use]
-extern crate lazy_static;
-
 use anyhow::Result;
 
-mod coloring;
-mod ena;
-mod fixups;
-mod fs;
-mod lca;
-mod lineage;
-mod results;
+use r2dt_utils::coloring::score::Policy;
+use r2dt_utils::coloring::{Alphabet, CountOptions};
+use r2dt_utils::fixups::apply::Extras;
+use r2dt_utils::fixups::summary::FailPolicy;
+use r2dt_utils::layout::{Layout, Sharding};
+use r2dt_utils::urs::Urs;
+use r2dt_utils::{coloring, ena, fixups, fs, lca, lineage, manifest, migrate, ncbi, qa, results};
+
+#[derive(Debug, StructOpt)]
+struct LayoutOptions {
+    #[structopt(
+        long = "layout",
+        default_value = "sharded:4",
+        about = "How diagrams are stored, such as flat, sharded:3,uncompressed or model-family"
+    )]
+    layout: Layout,
+
+    #[structopt(
+        long = "shard-by",
+        about = "Store taxid suffixed ids by urs or by urs-taxid"
+    )]
+    shard_by: Option<Sharding>,
+}
+
+impl LayoutOptions {
+    fn layout(&self) -> Layout {
+        return match self.shard_by {
+            Some(sharding) => Layout {
+                sharding,
+                ..self.layout
+            },
+            None => self.layout,
+        };
+    }
+}
 
 #[derive(Debug, StructOpt)]
 enum ColoringCommand {
     #[structopt(name = "tree", about = "Iterate over a tree and find parse all SVGS")]
     Tree {
+        #[structopt(
+            short = "j",
+            long = "jobs",
+            default_value = "1",
+            about = "Number of threads to parse SVGs with"
+        )]
+        jobs: usize,
+
+        #[structopt(
+            long = "positions",
+            about = "Write the class and position of every nucleotide instead of counts"
+        )]
+        positions: bool,
+
+        #[structopt(
+            long = "alphabet",
+            default_value = "iupac",
+            about = "Letters counted as nucleotides, iupac or a list such as ACGU"
+        )]
+        alphabet: Alphabet,
+
+        #[structopt(
+            long = "keep-going",
+            about = "Record diagrams which cannot be parsed in this CSV file and continue",
+            parse(from_os_str)
+        )]
+        keep_going: Option<PathBuf>,
+
         #[structopt(parse(from_os_str))]
         tree: PathBuf,
     },
+    #[structopt(
+        name = "score",
+        about = "Grade diagrams as pass, warn or fail from a CSV of color counts"
+    )]
+    Score {
+        #[structopt(
+            short = "l",
+            long = "lengths",
+            about = "A CSV of urs and length to compare the number of nucleotides to",
+            parse(from_os_str)
+        )]
+        lengths: Option<PathBuf>,
+
+        #[structopt(
+            short = "p",
+            long = "policy",
+            about = "A JSON file of warn and fail limits, instead of the defaults",
+            parse(from_os_str)
+        )]
+        policy: Option<PathBuf>,
+
+        #[structopt(parse(from_os_str))]
+        counts: PathBuf,
+    },
     #[structopt(name = "json-file", about = "Parse a JSON file of urs, layout of SVGS")]
     Json {
+        #[structopt(
+            long = "positions",
+            about = "Write the class and position of every nucleotide instead of counts"
+        )]
+        positions: bool,
+
+        #[structopt(
+            long = "alphabet",
+            default_value = "iupac",
+            about = "Letters counted as nucleotides, iupac or a list such as ACGU"
+        )]
+        alphabet: Alphabet,
+
+        #[structopt(
+            long = "keep-going",
+            about = "Record diagrams which cannot be parsed in this CSV file and continue",
+            parse(from_os_str)
+        )]
+        keep_going: Option<PathBuf>,
+
         #[structopt(parse(from_os_str))]
         file: PathBuf,
     },
@@ -35,12 +137,144 @@ enum FixupCommand {
         about = "Process a svg tree to find naming/compression/missing issues"
     )]
     Report {
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
+        #[structopt(
+            long = "deep",
+            about = "Decompress and parse every diagram to find damaged files"
+        )]
+        deep: bool,
+
+        #[structopt(
+            long = "check-gzip",
+            about = "Decompress every compressed diagram to find damaged files, without parsing them"
+        )]
+        check_gzip: bool,
+
+        #[structopt(
+            long = "exclude",
+            number_of_values = 1,
+            about = "A directory inside the tree to skip, such as a quarantine, may be given more than once",
+            parse(from_os_str)
+        )]
+        exclude: Vec<PathBuf>,
+
+        #[structopt(
+            long = "summary",
+            about = "Print counts of each status instead of a line per file"
+        )]
+        summary: bool,
+
+        #[structopt(
+            long = "top",
+            default_value = "10",
+            about = "Number of directories with the most problems to list in the summary"
+        )]
+        top: usize,
+
+        #[structopt(
+            long = "shard-depth",
+            default_value = "2",
+            about = "Number of directories below the tree to group the summary by"
+        )]
+        shard_depth: usize,
+
+        #[structopt(
+            long = "fail-on",
+            default_value = "never",
+            about = "Exit with an error if any of these statuses are found, may be never, any or a comma separated list"
+        )]
+        fail_on: FailPolicy,
+
         #[structopt(parse(from_os_str))]
         tree: PathBuf,
 
         #[structopt(parse(from_os_str))]
         required: PathBuf,
     },
+    #[structopt(
+        name = "apply",
+        about = "Move and compress files to fix the issues found in a report"
+    )]
+    Apply {
+        #[structopt(
+            short = "r",
+            long = "report",
+            about = "A report to apply, instead of recomputing it",
+            parse(from_os_str)
+        )]
+        report: Option<PathBuf>,
+
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
+        #[structopt(
+            short = "q",
+            long = "quarantine",
+            about = "Directory to move unknown files into",
+            parse(from_os_str)
+        )]
+        quarantine: Option<PathBuf>,
+
+        #[structopt(
+            short = "j",
+            long = "journal",
+            about = "A journal of completed actions, used to resume or undo a run",
+            parse(from_os_str)
+        )]
+        journal: Option<PathBuf>,
+
+        #[structopt(
+            long = "extras",
+            default_value = "keep",
+            about = "What to do with diagrams which are not required: keep, prune or archive:DIR"
+        )]
+        extras: Extras,
+
+        #[structopt(parse(from_os_str))]
+        tree: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        required: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "undo",
+        about = "Revert all actions recorded in a journal, then move it aside"
+    )]
+    Undo {
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+
+        #[structopt(parse(from_os_str))]
+        journal: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ManifestCommand {
+    #[structopt(
+        name = "create",
+        about = "List every diagram in a tree with its size and checksum"
+    )]
+    Create {
+        #[structopt(parse(from_os_str))]
+        tree: PathBuf,
+    },
+    #[structopt(
+        name = "diff",
+        about = "Report diagrams added, removed or changed between two manifests"
+    )]
+    Diff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -70,6 +304,15 @@ enum Command {
         )]
         rename_file: Option<PathBuf>,
 
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
+        #[structopt(
+            long = "incremental",
+            about = "Only write diagrams which are new or have changed"
+        )]
+        incremental: bool,
+
         #[structopt(
             name = "FILE",
             about = "A filename containing a list of result directories to take SVGs from",
@@ -95,6 +338,15 @@ enum Command {
         )]
         rename_file: Option<PathBuf>,
 
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
+        #[structopt(
+            long = "incremental",
+            about = "Only write diagrams which are new or have changed"
+        )]
+        incremental: bool,
+
         #[structopt(
             name = "FILE",
             about = "A filename containing the JSON encoded SVGs to split",
@@ -118,6 +370,49 @@ enum Command {
         #[structopt(short = "c", long = "chunk-size", default_value = "10")]
         chunk_size: usize,
 
+        #[structopt(
+            long = "major-ranks-only",
+            about = "Only output the major ranks, as older versions did"
+        )]
+        major_ranks_only: bool,
+
+        #[structopt(
+            long = "taxdump",
+            conflicts_with = "cache",
+            about = "A directory of NCBI taxdump files to use instead of ENA",
+            parse(from_os_str)
+        )]
+        taxdump: Option<PathBuf>,
+
+        #[structopt(
+            long = "cache",
+            about = "A file to cache fetched taxonomy information in",
+            parse(from_os_str)
+        )]
+        cache: Option<PathBuf>,
+
+        #[structopt(
+            long = "cache-only",
+            requires = "cache",
+            conflicts_with = "refresh",
+            about = "Only use cached taxonomy information, never contact ENA"
+        )]
+        cache_only: bool,
+
+        #[structopt(
+            long = "refresh",
+            requires = "cache",
+            about = "Fetch all taxids again and update the cache"
+        )]
+        refresh: bool,
+
+        #[structopt(
+            long = "expire-after",
+            requires = "cache",
+            about = "Refetch cached entries older than this many days"
+        )]
+        expire_after: Option<u64>,
+
         #[structopt(name = "FILE", parse(from_os_str))]
         filename: PathBuf,
     },
@@ -131,11 +426,70 @@ enum Command {
         assignments_filename: PathBuf,
     },
 
+    #[structopt(name = "manifest", about = "Record and compare the diagrams in a tree")]
+    Manifest {
+        #[structopt(subcommand)]
+        cmd: ManifestCommand,
+    },
+
+    #[structopt(
+        name = "migrate",
+        about = "Move a tree of diagrams from one layout to another"
+    )]
+    Migrate {
+        #[structopt(
+            long = "from",
+            default_value = "sharded:4",
+            about = "The layout the tree is currently in"
+        )]
+        from: Layout,
+
+        #[structopt(long = "to", about = "The layout to move the tree into")]
+        to: Layout,
+
+        #[structopt(
+            long = "target",
+            about = "Build the new tree in this directory, instead of in place",
+            parse(from_os_str)
+        )]
+        target: Option<PathBuf>,
+
+        #[structopt(
+            long = "hardlink",
+            about = "Hardlink diagrams into the new layout instead of moving them"
+        )]
+        hardlink: bool,
+
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+
+        #[structopt(
+            short = "m",
+            long = "manifest",
+            about = "File to record migrated diagrams in, used to resume a run",
+            parse(from_os_str)
+        )]
+        manifest: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        tree: PathBuf,
+    },
+
     #[structopt(name = "create-tree", about = "Command to generate the final tree")]
-    Fs { max_urs: String, base: PathBuf },
+    Fs {
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
+        max_urs: Urs,
+
+        base: PathBuf,
+    },
 
     #[structopt(name = "path-to", about = "Command to generate path for URS ids")]
     PathTo {
+        #[structopt(flatten)]
+        layout: LayoutOptions,
+
         #[structopt(name = "FILE", parse(from_os_str))]
         urs_filename: PathBuf,
 
@@ -147,6 +501,70 @@ enum Command {
         target_directory: PathBuf,
     },
 
+    #[structopt(
+        name = "qa",
+        about = "Join diagram metadata with color counts and flag diagrams to check"
+    )]
+    Qa {
+        #[structopt(
+            short = "c",
+            long = "counts",
+            about = "A CSV of color counts to use",
+            conflicts_with = "tree",
+            required_unless = "tree",
+            parse(from_os_str)
+        )]
+        counts: Option<PathBuf>,
+
+        #[structopt(
+            short = "t",
+            long = "tree",
+            about = "A tree of diagrams to count the colors of",
+            parse(from_os_str)
+        )]
+        tree: Option<PathBuf>,
+
+        #[structopt(
+            short = "j",
+            long = "jobs",
+            default_value = "1",
+            about = "Number of threads to parse SVGs with"
+        )]
+        jobs: usize,
+
+        #[structopt(
+            long = "alphabet",
+            default_value = "iupac",
+            about = "Letters counted as nucleotides in the tree, iupac or a list such as ACGU"
+        )]
+        alphabet: Alphabet,
+
+        #[structopt(
+            long = "keep-going",
+            about = "Record diagrams in the tree which cannot be parsed in this CSV file and continue",
+            requires = "tree",
+            parse(from_os_str)
+        )]
+        keep_going: Option<PathBuf>,
+
+        #[structopt(long = "max-overlap", default_value = "5")]
+        max_overlap: u64,
+
+        #[structopt(long = "min-coverage", default_value = "0.8")]
+        min_coverage: f64,
+
+        #[structopt(
+            short = "p",
+            long = "policy",
+            about = "A JSON file of warn and fail limits to grade color counts with, instead of the defaults",
+            parse(from_os_str)
+        )]
+        policy: Option<PathBuf>,
+
+        #[structopt(name = "METADATA", parse(from_os_str))]
+        metadata: PathBuf,
+    },
+
     #[structopt(name = "rename-metadata", about = "Parse a CSV and rename the URS ids")]
     RenameMetadata {
         #[structopt(name = "MAPPING", parse(from_os_str))]
@@ -193,44 +611,228 @@ pub fn main() -> Result<()> {
         2 => simplelog::LevelFilter::Debug,
         _ => simplelog::LevelFilter::Trace,
     };
-    simplelog::SimpleLogger::init(level, simplelog::Config::default())
+    // Log to stderr, so stdout only contains the output of the command.
+    simplelog::WriteLogger::init(level, simplelog::Config::default(), io::stderr())
         .unwrap_or_else(|_| eprintln!("Failed to create logger, ignore"));
 
     return match opt.cmd {
         Command::Coloring { cmd } => match cmd {
-            ColoringCommand::Tree { tree } => coloring::count_tree(tree),
-            ColoringCommand::Json { file } => coloring::count_json(file),
+            ColoringCommand::Tree {
+                jobs,
+                positions,
+                alphabet,
+                keep_going,
+                tree,
+            } => {
+                let options = CountOptions {
+                    alphabet,
+                    keep_going,
+                };
+                match positions {
+                    true => coloring::nucleotides_tree(tree, jobs, options, io::stdout()),
+                    false => coloring::count_tree(tree, jobs, options, io::stdout()),
+                }
+            }
+            ColoringCommand::Json {
+                positions,
+                alphabet,
+                keep_going,
+                file,
+            } => {
+                let options = CountOptions {
+                    alphabet,
+                    keep_going,
+                };
+                match positions {
+                    true => coloring::nucleotides_json(file, options, io::stdout()),
+                    false => coloring::count_json(file, options, io::stdout()),
+                }
+            }
+            ColoringCommand::Score {
+                lengths,
+                policy,
+                counts,
+            } => coloring::score::write_scores(counts, lengths, policy, io::stdout()),
         },
         Command::Fixups { cmd } => match cmd {
-            FixupCommand::Report { tree, required } => fixups::write_report(&tree, required),
+            FixupCommand::Report {
+                layout,
+                deep,
+                check_gzip,
+                exclude,
+                summary,
+                top,
+                shard_depth,
+                fail_on,
+                tree,
+                required,
+            } => {
+                let options = fixups::CheckOptions {
+                    layout: layout.layout(),
+                    deep,
+                    check_gzip,
+                    exclude,
+                };
+                let report = fixups::ReportOptions {
+                    summary,
+                    top,
+                    shard_depth,
+                    fail_on,
+                };
+                fixups::write_report(&tree, &options, required, &report, io::stdout())
+            }
+            FixupCommand::Apply {
+                report,
+                dry_run,
+                layout,
+                quarantine,
+                journal,
+                extras,
+                tree,
+                required,
+            } => {
+                let options = fixups::apply::ApplyOptions {
+                    dry_run,
+                    layout: layout.layout(),
+                    quarantine,
+                    journal,
+                    extras,
+                };
+                fixups::apply::apply(&tree, report, required, options, io::stdout())
+            }
+            FixupCommand::Undo { dry_run, journal } => {
+                fixups::apply::undo(journal, dry_run, io::stdout())
+            }
         },
         Command::Lineage {
             chunk_size,
+            major_ranks_only,
+            taxdump,
+            cache,
+            cache_only,
+            refresh,
+            expire_after,
             filename,
-        } => lineage::write_lineage(chunk_size, filename),
+        } => {
+            let mode = match (cache_only, refresh) {
+                (true, _) => ena::cache::CacheMode::CacheOnly,
+                (_, true) => ena::cache::CacheMode::Refresh,
+                _ => ena::cache::CacheMode::Normal,
+            };
+            let expire_after = expire_after.map(|d| Duration::from_secs(d * 24 * 60 * 60));
+            let source = match (taxdump, cache) {
+                (Some(directory), _) => {
+                    lineage::TaxonomySource::Taxdump(ncbi::Taxdump::load(&directory)?)
+                }
+                (None, Some(path)) => lineage::TaxonomySource::Cached(
+                    ena::cache::TaxonomyCache::open(&path, mode, expire_after)?,
+                ),
+                (None, None) => lineage::TaxonomySource::Ena,
+            };
+            lineage::write_lineage(chunk_size, filename, source, major_ranks_only, io::stdout())
+        }
         Command::Lca {
             taxid_filename,
             assignments_filename,
-        } => lca::write_lca(taxid_filename, assignments_filename),
+        } => lca::write_lca(taxid_filename, assignments_filename, io::stdout()),
         Command::Move {
             filename,
             target_directory,
             rename_file,
-        } => results::move_file(filename, target_directory, rename_file),
+            layout,
+            incremental,
+        } => {
+            let options = results::WriteOptions {
+                layout: layout.layout(),
+                incremental,
+            };
+            results::move_file(filename, target_directory, rename_file, &options)
+        }
         Command::Split {
             filename,
             target_directory,
             rename_file,
-        } => results::split_file(filename, target_directory, rename_file),
-        Command::Fs { max_urs, base } => fs::create_tree(&max_urs, &base),
+            layout,
+            incremental,
+        } => {
+            let options = results::WriteOptions {
+                layout: layout.layout(),
+                incremental,
+            };
+            results::split_file(filename, target_directory, rename_file, &options)
+        }
+        Command::Manifest { cmd } => match cmd {
+            ManifestCommand::Create { tree } => manifest::write_manifest(tree, io::stdout()),
+            ManifestCommand::Diff { old, new } => manifest::write_diff(old, new, io::stdout()),
+        },
+        Command::Migrate {
+            from,
+            to,
+            target,
+            hardlink,
+            dry_run,
+            manifest,
+            tree,
+        } => {
+            let options = migrate::MigrateOptions {
+                from,
+                to,
+                target,
+                hardlink,
+                dry_run,
+                manifest,
+            };
+            migrate::migrate(&tree, options, io::stdout())
+        }
+        Command::Fs {
+            layout,
+            max_urs,
+            base,
+        } => fs::create_tree(&max_urs, &base, &layout.layout()),
         Command::PathTo {
+            layout,
             urs_filename,
             target_directory,
-        } => fs::paths(urs_filename, target_directory),
+        } => fs::paths(
+            urs_filename,
+            target_directory,
+            &layout.layout(),
+            io::stdout(),
+        ),
+        Command::Qa {
+            counts,
+            tree,
+            jobs,
+            alphabet,
+            keep_going,
+            max_overlap,
+            min_coverage,
+            policy,
+            metadata,
+        } => {
+            let source = match counts {
+                Some(counts) => qa::CountSource::Load(counts),
+                None => qa::CountSource::Compute {
+                    tree: tree.expect("--tree is required without --counts"),
+                    jobs,
+                    alphabet,
+                    keep_going,
+                },
+            };
+            let options = qa::QaOptions {
+                max_overlap,
+                min_coverage,
+                policy: match policy {
+                    Some(path) => Policy::from_path(&path)?,
+                    None => Policy::default(),
+                },
+            };
+            qa::write_qa(metadata, source, &options, io::stdout())
+        }
         Command::RenameMetadata {
             mapping_file,
             filename,
-        } => results::rename_metadata(mapping_file, filename),
+        } => results::rename_metadata(mapping_file, filename, io::stdout()),
         Command::TransferData {
             access_token,
             host,
@@ -0,0 +1,258 @@
+//! Record exactly which diagrams are in a tree, and compare the records of
+//! two releases.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use flate2::read::GzDecoder;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use anyhow::Result;
+
+use crate::coloring;
+use crate::fixups::urs_utils;
+use crate::urs::UrsId;
+
+/// A single diagram in the tree. The checksum and uncompressed size are of
+/// the SVG itself, so recompressing a diagram does not change them. The path
+/// is relative to the tree. Diagrams which cannot be read to the end, such as
+/// damaged gzip files, have an empty checksum and the reason in `error`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub urs: UrsId,
+    pub path: PathBuf,
+    pub compressed_size: Option<u64>,
+    pub uncompressed_size: u64,
+    pub sha256: String,
+    pub mtime: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A diagram which differs between two manifests.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Difference {
+    pub change: Change,
+    pub urs: UrsId,
+    pub old_path: Option<PathBuf>,
+    pub new_path: Option<PathBuf>,
+    pub old_sha256: Option<String>,
+    pub new_sha256: Option<String>,
+}
+
+/// The size and SHA-256 of everything in `reader`.
+pub fn digest<R: Read>(mut reader: R) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut reader, &mut hasher)?;
+    return Ok((size, hex::encode(hasher.finalize())));
+}
+
+/// Build the entry for the diagram at `path`, or `None` if the filename does
+/// not contain a URS.
+pub fn entry(base: &Path, path: &Path) -> Result<Option<Entry>> {
+    let urs = match urs_utils::filename_urs(path) {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+
+    let metadata = path.metadata()?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file = BufReader::new(File::open(path)?);
+    let compressed = path.extension().is_some_and(|e| e == "gz");
+    let digested = match compressed {
+        true => digest(GzDecoder::new(file)),
+        false => digest(file),
+    };
+    let (uncompressed_size, sha256, error) = match digested {
+        Ok((size, sha256)) => (size, sha256, None),
+        Err(err) => {
+            log::warn!("Failed to read {:?}: {}", path, err);
+            (0, String::new(), Some(err.to_string()))
+        }
+    };
+
+    return Ok(Some(Entry {
+        urs,
+        path: PathBuf::from(path.strip_prefix(base).unwrap_or(path)),
+        compressed_size: match compressed {
+            true => Some(metadata.len()),
+            false => None,
+        },
+        uncompressed_size,
+        sha256,
+        mtime,
+        error,
+    }));
+}
+
+/// Load a manifest written by `write_manifest`.
+pub fn load(filename: &PathBuf) -> Result<Vec<Entry>> {
+    let reader = BufReader::new(File::open(filename)?);
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut entries = Vec::new();
+    for entry in reader.deserialize() {
+        entries.push(entry?);
+    }
+    return Ok(entries);
+}
+
+fn by_urs(entries: Vec<Entry>) -> BTreeMap<UrsId, Entry> {
+    let mut mapping = BTreeMap::new();
+    for entry in entries {
+        if let Some(previous) = mapping.insert(entry.urs, entry) {
+            log::warn!("Multiple diagrams for {}", previous.urs);
+        }
+    }
+    return mapping;
+}
+
+/// Find all diagrams which were added, removed, or whose content changed
+/// between two manifests, ordered by URS.
+pub fn diff(old: Vec<Entry>, new: Vec<Entry>) -> Vec<Difference> {
+    let mut old = by_urs(old);
+    let mut differences = Vec::new();
+    for (urs, entry) in by_urs(new) {
+        let previous = old.remove(&urs);
+        let change = match &previous {
+            None => Change::Added,
+            Some(p) if p.sha256 != entry.sha256 => Change::Changed,
+            Some(_) => continue,
+        };
+        differences.push(Difference {
+            change,
+            urs,
+            old_path: previous.as_ref().map(|p| p.path.clone()),
+            new_path: Some(entry.path),
+            old_sha256: previous.map(|p| p.sha256),
+            new_sha256: Some(entry.sha256),
+        });
+    }
+
+    for (urs, entry) in old {
+        differences.push(Difference {
+            change: Change::Removed,
+            urs,
+            old_path: Some(entry.path),
+            new_path: None,
+            old_sha256: Some(entry.sha256),
+            new_sha256: None,
+        });
+    }
+    differences.sort_by_key(|d| d.urs);
+    return differences;
+}
+
+/// Write the entry of every diagram in the tree to `out` as CSV.
+pub fn write_manifest<W: Write>(tree: PathBuf, out: W) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(out);
+    for path in coloring::svg_paths(tree.clone())? {
+        match entry(&tree, &path)? {
+            Some(entry) => wtr.serialize(entry)?,
+            None => log::debug!("No URS in {:?}", path),
+        }
+    }
+    wtr.flush()?;
+    return Ok(());
+}
+
+/// Write the differences between two manifests to `out` as CSV.
+pub fn write_diff<W: Write>(old: PathBuf, new: PathBuf, out: W) -> Result<()> {
+    let differences = diff(load(&old)?, load(&new)?);
+    let mut wtr = csv::Writer::from_writer(out);
+    for change in &[Change::Added, Change::Removed, Change::Changed] {
+        let count = differences.iter().filter(|d| d.change == *change).count();
+        log::info!("{:?}: {}", change, count);
+    }
+    for difference in differences {
+        wtr.serialize(difference)?;
+    }
+    wtr.flush()?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn entry(urs: &str, sha256: &str) -> Entry {
+        return Entry {
+            urs: urs.parse().unwrap(),
+            path: PathBuf::from(format!("{}.svg.gz", urs)),
+            compressed_size: Some(10),
+            uncompressed_size: 20,
+            sha256: sha256.to_string(),
+            mtime: 0,
+            error: None,
+        };
+    }
+
+    #[test]
+    fn digests_content() -> Result<()> {
+        assert_eq!(
+            digest("abc".as_bytes())?,
+            (
+                3,
+                String::from("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+            )
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn records_unreadable_diagrams() -> Result<()> {
+        let dir = TempDir::new("manifest")?;
+        let path = dir.write("URS0000000372.svg.gz", "not gzip")?;
+        let found = super::entry(dir.path(), &path)?.unwrap();
+        assert_eq!(found.path, PathBuf::from("URS0000000372.svg.gz"));
+        assert_eq!(found.sha256, "");
+        assert!(found.error.is_some());
+        return Ok(());
+    }
+
+    #[test]
+    fn finds_differences() {
+        let old = vec![
+            entry("URS0000000001", "a"),
+            entry("URS0000000002", "b"),
+            entry("URS0000000003", "c"),
+        ];
+        let new = vec![
+            entry("URS0000000004", "d"),
+            entry("URS0000000003", "c"),
+            entry("URS0000000002", "e"),
+        ];
+        let changes: Vec<(String, Change)> = diff(old, new)
+            .into_iter()
+            .map(|d| (d.urs.to_string(), d.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (String::from("URS0000000001"), Change::Removed),
+                (String::from("URS0000000002"), Change::Changed),
+                (String::from("URS0000000004"), Change::Added),
+            ]
+        );
+    }
+}
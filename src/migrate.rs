@@ -0,0 +1,333 @@
+//! Move a tree of diagrams from one layout to another.
+
+use std::collections::HashSet;
+use std::fs::{create_dir_all, hard_link, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use walkdir::WalkDir;
+
+use anyhow::{anyhow, Result};
+
+use crate::fixups::apply::Action;
+use crate::fixups::{layout_model, urs_utils};
+use crate::layout::{Layout, Scheme};
+use crate::urs::UrsId;
+
+pub struct MigrateOptions {
+    pub from: Layout,
+    pub to: Layout,
+    /// Directory to build the new tree in, defaults to the source tree.
+    pub target: Option<PathBuf>,
+    /// Hardlink files into the new tree instead of moving them.
+    pub hardlink: bool,
+    pub dry_run: bool,
+    /// File recording every migrated diagram, used to resume a run.
+    pub manifest: PathBuf,
+}
+
+/// One line of the manifest.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Migrated {
+    pub urs: UrsId,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Where the diagram at `path` belongs in the target layout, or `None` if it
+/// is not a diagram stored in the source layout.
+fn destination(
+    base: &Path,
+    target: &Path,
+    from: &Layout,
+    to: &Layout,
+    path: &Path,
+) -> Option<Migrated> {
+    let urs = urs_utils::filename_urs(path)?;
+    let model = layout_model(path, &urs, from);
+    if from.path_for(base, &urs, model) != path {
+        return None;
+    }
+    return Some(Migrated {
+        urs,
+        from: PathBuf::from(path),
+        to: to.path_for(target, &urs, model),
+    });
+}
+
+fn load_manifest(path: &PathBuf) -> Result<Vec<Migrated>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    return Ok(entries);
+}
+
+impl Migrated {
+    fn perform(&self, options: &MigrateOptions) -> Result<()> {
+        if options.hardlink {
+            if let Some(parent) = self.to.parent() {
+                create_dir_all(parent)?;
+            }
+            hard_link(&self.from, &self.to)?;
+            return Ok(());
+        }
+
+        let (from, to) = (self.from.clone(), self.to.clone());
+        let action = match (options.from.compressed, options.to.compressed) {
+            (false, true) => Action::Compress { from, to },
+            (true, false) => Action::Decompress { from, to },
+            _ => Action::Move { from, to },
+        };
+        return action.perform();
+    }
+}
+
+/// Move, or hardlink, every diagram in `tree` into the location the target
+/// layout expects. Each diagram is recorded in the manifest before it is
+/// migrated, and recorded diagrams already in their new location are skipped,
+/// so an interrupted run can be resumed. Fails if, at the end, any diagram in the
+/// manifest is not in its new location, or any diagram was not where the
+/// source layout expects it. Each migrated diagram is written to `out` as a
+/// JSON line.
+pub fn migrate<W: Write>(tree: &PathBuf, options: MigrateOptions, mut out: W) -> Result<()> {
+    if options.hardlink && options.from.compressed != options.to.compressed {
+        return Err(anyhow!(
+            "Cannot hardlink between layouts which differ in compression"
+        ));
+    }
+    if options.from == options.to && options.target.is_none() {
+        return Err(anyhow!("Source and target layouts are the same"));
+    }
+    if options.to.scheme == Scheme::ModelFamily && options.from.scheme != Scheme::ModelFamily {
+        return Err(anyhow!(
+            "Can only migrate to the model family layout from another model family layout, \
+             as the model of each diagram is not known"
+        ));
+    }
+
+    let target = options.target.clone().unwrap_or_else(|| tree.clone());
+    let mut manifest = match options.manifest.exists() {
+        true => load_manifest(&options.manifest)?,
+        false => Vec::new(),
+    };
+    let completed: HashSet<PathBuf> = manifest.iter().map(|m| m.from.clone()).collect();
+    let migrated_to: HashSet<PathBuf> = manifest.iter().map(|m| m.to.clone()).collect();
+    if !completed.is_empty() {
+        log::info!("Resuming with {} migrated diagrams", completed.len());
+    }
+
+    // Find everything first, as files may be moved into the tree being walked.
+    let mut found = Vec::new();
+    let mut misplaced = Vec::new();
+    let mut unrecognized = 0;
+    for entry in WalkDir::new(tree).into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match destination(tree, &target, &options.from, &options.to, entry.path()) {
+            Some(migrated) => found.push(migrated),
+            None if migrated_to.contains(entry.path()) => (),
+            None if urs_utils::filename_urs(entry.path()).is_some() => {
+                log::warn!("Not migrating misplaced diagram {:?}", entry.path());
+                misplaced.push(PathBuf::from(entry.path()));
+            }
+            None => {
+                log::debug!("Not migrating {:?}", entry.path());
+                unrecognized += 1;
+            }
+        }
+    }
+
+    let mut writer = match options.dry_run {
+        true => None,
+        false => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&options.manifest)?;
+            Some(BufWriter::new(file))
+        }
+    };
+
+    let mut moved = 0;
+    let mut conflicts = 0;
+    let mut resumed = 0;
+    for migrated in found {
+        // Diagrams are recorded before they are migrated, so a recorded
+        // diagram is only done once it is in its new location.
+        let recorded = completed.contains(&migrated.from);
+        if recorded && migrated.to.exists() {
+            resumed += 1;
+            continue;
+        }
+        if migrated.to.exists() {
+            log::warn!("Not overwriting existing file for {:?}", &migrated);
+            conflicts += 1;
+            continue;
+        }
+
+        writeln!(out, "{}", serde_json::to_string(&migrated)?)?;
+        moved += 1;
+        if let Some(writer) = &mut writer {
+            if !recorded {
+                writeln!(writer, "{}", serde_json::to_string(&migrated)?)?;
+                writer.flush()?;
+            }
+            migrated.perform(&options)?;
+            if !recorded {
+                manifest.push(migrated);
+            }
+        }
+    }
+
+    log::info!(
+        "Migrated {} diagrams, {} from a previous run, {} conflicts, {} misplaced diagrams, \
+         {} other files",
+        moved,
+        resumed,
+        conflicts,
+        misplaced.len(),
+        unrecognized
+    );
+    if options.dry_run {
+        return Ok(());
+    }
+
+    let missing: Vec<&Migrated> = manifest.iter().filter(|m| !m.to.exists()).collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "{} of {} migrated diagrams are missing from the new tree, first: {:?}",
+            missing.len(),
+            manifest.len(),
+            missing[0]
+        ));
+    }
+    if conflicts > 0 {
+        return Err(anyhow!(
+            "{} diagrams were not migrated as the target already existed",
+            conflicts
+        ));
+    }
+    if !misplaced.is_empty() {
+        return Err(anyhow!(
+            "{} diagrams were not migrated as they are not where the source layout \
+             expects, first: {:?}",
+            misplaced.len(),
+            misplaced[0]
+        ));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn finds_destination_in_new_layout() -> Result<()> {
+        let from = Layout::default();
+        let to: Layout = "flat,uncompressed".parse()?;
+        let base = Path::new("foo");
+        let path = Path::new("foo/URS/00/00/00/03/URS0000000372.svg.gz");
+        assert_eq!(
+            destination(base, base, &from, &to, path),
+            Some(Migrated {
+                urs: "URS0000000372".parse()?,
+                from: PathBuf::from(path),
+                to: PathBuf::from("foo/URS0000000372.svg"),
+            })
+        );
+        assert_eq!(
+            destination(base, base, &from, &to, Path::new("foo/URS0000000372.svg")),
+            None
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_model_between_family_layouts() -> Result<()> {
+        let from: Layout = "model-family".parse()?;
+        let to: Layout = "model-family,uncompressed".parse()?;
+        let path = Path::new("foo/RF00001/URS0000000372.svg.gz");
+        let migrated = destination(Path::new("foo"), Path::new("bar"), &from, &to, path);
+        assert_eq!(
+            migrated.map(|m| m.to),
+            Some(PathBuf::from("bar/RF00001/URS0000000372.svg"))
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_model_of_diagrams_split_by_taxid() -> Result<()> {
+        let from: Layout = "model-family,by-taxid".parse()?;
+        let to: Layout = "model-family,uncompressed".parse()?;
+        let path = Path::new("foo/RF00001/9606/URS0000000372_9606.svg.gz");
+        let migrated = destination(Path::new("foo"), Path::new("bar"), &from, &to, path);
+        assert_eq!(
+            migrated.map(|m| m.to),
+            Some(PathBuf::from("bar/RF00001/URS0000000372_9606.svg"))
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn resumes_after_recording_a_diagram() -> Result<()> {
+        let dir = TempDir::new("migrate")?;
+        let tree = dir.path().join("tree");
+        let moved = Migrated {
+            urs: "URS0000000372".parse()?,
+            from: dir.write("tree/URS0000000372.svg", "")?,
+            to: tree.join("URS/00/00/00/03/URS0000000372.svg"),
+        };
+        let pending = Migrated {
+            urs: "URS0000000373".parse()?,
+            from: dir.write("tree/URS0000000373.svg", "")?,
+            to: tree.join("URS/00/00/00/03/URS0000000373.svg"),
+        };
+        // Interrupted after recording both diagrams, but moving only one.
+        std::fs::create_dir_all(moved.to.parent().unwrap())?;
+        std::fs::rename(&moved.from, &moved.to)?;
+        let lines = format!(
+            "{}\n{}\n",
+            serde_json::to_string(&moved)?,
+            serde_json::to_string(&pending)?
+        );
+        let manifest = dir.write("manifest", &lines)?;
+
+        let options = MigrateOptions {
+            from: "flat,uncompressed".parse()?,
+            to: "uncompressed".parse()?,
+            target: None,
+            hardlink: false,
+            dry_run: false,
+            manifest: manifest.clone(),
+        };
+        migrate(&tree, options, std::io::sink())?;
+        assert!(pending.to.exists() && !pending.from.exists());
+        assert_eq!(load_manifest(&manifest)?, vec![moved, pending]);
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_unknown_models() -> Result<()> {
+        let options = MigrateOptions {
+            from: Layout::default(),
+            to: "model-family".parse()?,
+            target: None,
+            hardlink: false,
+            dry_run: true,
+            manifest: PathBuf::from("manifest"),
+        };
+        assert!(migrate(&PathBuf::from("foo"), options, std::io::sink()).is_err());
+        return Ok(());
+    }
+}
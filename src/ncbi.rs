@@ -0,0 +1,259 @@
+//! Load taxonomy information from an NCBI taxdump.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use anyhow::{anyhow, Result};
+
+use crate::ena::{EnaLineage, EnaLineageTaxon, EnaTaxonInfo};
+
+/// The taxid of the root of the taxonomy, which ENA leaves out of lineages.
+const ROOT: usize = 1;
+
+struct Node {
+    parent: usize,
+    rank: String,
+}
+
+/// The taxonomy from an NCBI taxdump, as found in `taxdump.tar.gz` from
+/// <https://ftp.ncbi.nih.gov/pub/taxonomy/>.
+pub struct Taxdump {
+    nodes: HashMap<usize, Node>,
+    names: HashMap<usize, String>,
+    merged: HashMap<usize, usize>,
+    deleted: HashSet<usize>,
+}
+
+fn fields(line: &str) -> Vec<&str> {
+    return line
+        .trim_end_matches("\t|")
+        .split("\t|\t")
+        .map(|f| f.trim())
+        .collect();
+}
+
+fn parse_taxid(raw: &str) -> Result<usize> {
+    return raw
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid taxid '{}' in taxdump", raw));
+}
+
+fn open(directory: &PathBuf, name: &str) -> Result<BufReader<File>> {
+    let mut path = PathBuf::from(directory);
+    path.push(name);
+    let file = File::open(&path).map_err(|e| anyhow!("Could not open {:?}: {}", path, e))?;
+    return Ok(BufReader::new(file));
+}
+
+impl Taxdump {
+    pub fn load(directory: &PathBuf) -> Result<Self> {
+        let taxdump = Self::from_readers(
+            open(directory, "nodes.dmp")?,
+            open(directory, "names.dmp")?,
+            open(directory, "merged.dmp")?,
+            open(directory, "delnodes.dmp")?,
+        )?;
+        info!(
+            "Loaded {} taxa, {} merged and {} deleted taxids from {:?}",
+            taxdump.nodes.len(),
+            taxdump.merged.len(),
+            taxdump.deleted.len(),
+            directory
+        );
+        return Ok(taxdump);
+    }
+
+    pub fn from_readers<N, M, G, D>(nodes: N, names: M, merged: G, deleted: D) -> Result<Self>
+    where
+        N: BufRead,
+        M: BufRead,
+        G: BufRead,
+        D: BufRead,
+    {
+        let mut taxdump = Self {
+            nodes: HashMap::new(),
+            names: HashMap::new(),
+            merged: HashMap::new(),
+            deleted: HashSet::new(),
+        };
+
+        for line in nodes.lines() {
+            let line = line?;
+            let parts = fields(&line);
+            if parts.len() < 3 {
+                return Err(anyhow!("Invalid line in nodes.dmp: {}", line));
+            }
+            let node = Node {
+                parent: parse_taxid(parts[1])?,
+                rank: parts[2].to_string(),
+            };
+            taxdump.nodes.insert(parse_taxid(parts[0])?, node);
+        }
+
+        for line in names.lines() {
+            let line = line?;
+            let parts = fields(&line);
+            if parts.len() < 4 {
+                return Err(anyhow!("Invalid line in names.dmp: {}", line));
+            }
+            if parts[3] == "scientific name" {
+                taxdump
+                    .names
+                    .insert(parse_taxid(parts[0])?, parts[1].to_string());
+            }
+        }
+
+        for line in merged.lines() {
+            let line = line?;
+            let parts = fields(&line);
+            if parts.len() < 2 {
+                return Err(anyhow!("Invalid line in merged.dmp: {}", line));
+            }
+            taxdump
+                .merged
+                .insert(parse_taxid(parts[0])?, parse_taxid(parts[1])?);
+        }
+
+        for line in deleted.lines() {
+            let line = line?;
+            taxdump.deleted.insert(parse_taxid(fields(&line)[0])?);
+        }
+
+        return Ok(taxdump);
+    }
+
+    fn rank(&self, taxid: usize) -> Option<String> {
+        return self
+            .nodes
+            .get(&taxid)
+            .map(|n| n.rank.clone())
+            .filter(|r| r != "no rank");
+    }
+
+    fn name(&self, taxid: usize) -> String {
+        return self.names.get(&taxid).cloned().unwrap_or_default();
+    }
+
+    pub fn is_deleted(&self, taxid: usize) -> bool {
+        return self.deleted.contains(&taxid);
+    }
+
+    /// Build the same information ENA would provide for the given taxid.
+    /// Merged taxids produce the info of the taxon they were merged into. Like
+    /// ENA the lineage stops below the root, taxid 1. A lineage which visits a
+    /// taxid twice, which only a malformed nodes.dmp can produce, is treated
+    /// as unknown.
+    pub fn taxon(&self, taxid: usize) -> Option<EnaTaxonInfo> {
+        if self.is_deleted(taxid) {
+            return None;
+        }
+        let taxid = match self.merged.get(&taxid) {
+            Some(current) => *current,
+            None => taxid,
+        };
+        let node = self.nodes.get(&taxid)?;
+
+        let mut taxons = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = taxid;
+        let mut parent = node.parent;
+        while parent != current && parent != ROOT {
+            if !seen.insert(parent) {
+                warn!("The lineage of {} contains a cycle at {}", taxid, parent);
+                return None;
+            }
+            taxons.push(EnaLineageTaxon {
+                name: self.name(parent),
+                taxid: parent,
+                rank: self.rank(parent),
+            });
+            current = parent;
+            parent = self.nodes.get(&current)?.parent;
+        }
+
+        return Some(EnaTaxonInfo {
+            name: self.name(taxid),
+            taxid,
+            rank: self.rank(taxid),
+            lineage: EnaLineage { taxons },
+        });
+    }
+
+    pub fn lookup(&self, taxids: &[usize]) -> HashMap<usize, EnaTaxonInfo> {
+        return taxids
+            .iter()
+            .filter_map(|t| self.taxon(*t).map(|info| (*t, info)))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: &str = "1\t|\t1\t|\tno rank\t|\t\t|
+131567\t|\t1\t|\tno rank\t|\t\t|
+2759\t|\t131567\t|\tsuperkingdom\t|\t\t|
+9605\t|\t2759\t|\tgenus\t|\t\t|
+9606\t|\t9605\t|\tspecies\t|\t\t|
+";
+
+    const NAMES: &str = "1\t|\troot\t|\t\t|\tscientific name\t|
+131567\t|\tcellular organisms\t|\t\t|\tscientific name\t|
+2759\t|\tEukaryota\t|\t\t|\tscientific name\t|
+9605\t|\tHomo\t|\t\t|\tscientific name\t|
+9606\t|\tHomo sapiens\t|\t\t|\tscientific name\t|
+9606\t|\thuman\t|\t\t|\tgenbank common name\t|
+";
+
+    fn taxdump() -> Taxdump {
+        return Taxdump::from_readers(
+            NODES.as_bytes(),
+            NAMES.as_bytes(),
+            "63221\t|\t9606\t|\n".as_bytes(),
+            "12\t|\n".as_bytes(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn builds_lineage_from_taxdump() {
+        let taxon = taxdump().taxon(9606).unwrap();
+        assert_eq!(taxon.name, "Homo sapiens");
+        assert_eq!(taxon.rank, Some(String::from("species")));
+        let parents: Vec<usize> = taxon.parent_taxons().iter().map(|t| t.taxid).collect();
+        assert_eq!(parents, vec![9605, 2759, 131567]);
+    }
+
+    #[test]
+    fn stops_at_cycles() -> Result<()> {
+        let taxdump = Taxdump::from_readers(
+            "9605\t|\t9606\t|\tgenus\t|\n9606\t|\t9605\t|\tspecies\t|\n".as_bytes(),
+            "".as_bytes(),
+            "".as_bytes(),
+            "".as_bytes(),
+        )?;
+        assert!(taxdump.taxon(9606).is_none());
+        return Ok(());
+    }
+
+    #[test]
+    fn follows_merged_taxids() {
+        let taxon = taxdump().taxon(63221).unwrap();
+        assert_eq!(taxon.taxid, 9606);
+        assert_eq!(taxon.name, "Homo sapiens");
+    }
+
+    #[test]
+    fn skips_deleted_and_unknown_taxids() {
+        let taxdump = taxdump();
+        assert!(taxdump.is_deleted(12));
+        assert!(taxdump.taxon(12).is_none());
+        assert!(taxdump.taxon(7).is_none());
+    }
+}
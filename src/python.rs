@@ -0,0 +1,285 @@
+//! Python bindings, built when the `python` feature is enabled. These expose
+//! the same path, URS, coloring and LCA logic the command line tools use.
+//! Their tests run with `cargo test --features python`.
+
+use std::path::PathBuf;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use quick_xml::Reader;
+
+use serde::Serialize;
+
+use crate::coloring;
+use crate::fixups::urs_utils;
+use crate::layout::{Layout, Sharding};
+use crate::lca;
+use crate::urs::{Urs, UrsId};
+
+fn value_error(error: anyhow::Error) -> PyErr {
+    return PyValueError::new_err(error.to_string());
+}
+
+/// The name `value` is given in the output of the command line tools, `None`
+/// if it is missing.
+fn serialized_name<T: Serialize>(value: &T) -> anyhow::Result<Option<String>> {
+    return Ok(serde_json::to_value(value)?.as_str().map(String::from));
+}
+
+impl<'source> FromPyObject<'source> for Urs {
+    fn extract(value: &'source PyAny) -> PyResult<Self> {
+        return value.extract::<&str>()?.parse().map_err(value_error);
+    }
+}
+
+impl IntoPy<PyObject> for Urs {
+    fn into_py(self, py: Python) -> PyObject {
+        return self.to_string().into_py(py);
+    }
+}
+
+impl<'source> FromPyObject<'source> for UrsId {
+    fn extract(value: &'source PyAny) -> PyResult<Self> {
+        return value.extract::<&str>()?.parse().map_err(value_error);
+    }
+}
+
+impl IntoPy<PyObject> for UrsId {
+    fn into_py(self, py: Python) -> PyObject {
+        return self.to_string().into_py(py);
+    }
+}
+
+impl<'source> FromPyObject<'source> for Layout {
+    fn extract(value: &'source PyAny) -> PyResult<Self> {
+        return value.extract::<&str>()?.parse().map_err(value_error);
+    }
+}
+
+fn with_taxid(layout: Layout, by_taxid: bool) -> Layout {
+    return match by_taxid {
+        true => Layout {
+            sharding: Sharding::UrsTaxid,
+            ..layout
+        },
+        false => layout,
+    };
+}
+
+/// The final path of the diagram for `urs` in the tree at `base`, using a
+/// layout such as `"flat,uncompressed"`. With `by_taxid` diagrams of taxid
+/// suffixed ids go in a directory per taxid.
+#[pyfunction]
+#[pyo3(signature = (base, urs, layout = Layout::default(), model = None, by_taxid = false))]
+fn path_for(
+    base: PathBuf,
+    urs: UrsId,
+    layout: Layout,
+    model: Option<&str>,
+    by_taxid: bool,
+) -> PathBuf {
+    return with_taxid(layout, by_taxid).path_for(&base, &urs, model);
+}
+
+/// The directory the diagram for `urs` is stored in.
+#[pyfunction]
+#[pyo3(signature = (base, urs, layout = Layout::default(), model = None, by_taxid = false))]
+fn directory_path(
+    base: PathBuf,
+    urs: UrsId,
+    layout: Layout,
+    model: Option<&str>,
+    by_taxid: bool,
+) -> PathBuf {
+    return with_taxid(layout, by_taxid).directory_path(&base, &urs, model);
+}
+
+#[pyfunction]
+fn looks_like_urs(urs: &str) -> bool {
+    return urs_utils::looks_like_urs(urs);
+}
+
+/// Extract the URS from a diagram filename, or `None` if there is none.
+#[pyfunction]
+fn filename_urs(path: PathBuf) -> Option<UrsId> {
+    return urs_utils::filename_urs(&path);
+}
+
+#[pyfunction]
+fn urs_to_index(urs: Urs) -> u64 {
+    return urs.index();
+}
+
+#[pyfunction]
+fn int_to_urs(index: u64) -> PyResult<Urs> {
+    return Urs::from_index(index).map_err(value_error);
+}
+
+fn count_text(urs: UrsId, svg: &str, alphabet: &str) -> anyhow::Result<coloring::Counts> {
+    let alphabet = alphabet.parse()?;
+    let mut reader = Reader::from_str(svg);
+    return coloring::count_reader(urs, &mut reader, &alphabet);
+}
+
+/// Count the colors of nucleotides in the text of an SVG.
+#[pyfunction]
+#[pyo3(signature = (urs, svg, alphabet = "iupac"))]
+fn count_svg(urs: UrsId, svg: &str, alphabet: &str) -> PyResult<coloring::Counts> {
+    return count_text(urs, svg, alphabet).map_err(value_error);
+}
+
+/// Count the colors of nucleotides in an SVG file, which may be compressed.
+#[pyfunction]
+#[pyo3(signature = (path, alphabet = "iupac"))]
+fn count_file(path: PathBuf, alphabet: &str) -> PyResult<coloring::Counts> {
+    let alphabet = alphabet.parse().map_err(value_error)?;
+    return coloring::count_path(&path, &alphabet).map_err(value_error);
+}
+
+/// The lineages of a set of taxids, as produced by the `lineage` command.
+#[pyclass(name = "TreeInfo")]
+struct PyTreeInfo {
+    trees: lca::TreeInfo,
+}
+
+#[pymethods]
+impl PyTreeInfo {
+    /// Load a file of JSON lines produced by the `lineage` command.
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let trees = lca::TreeInfo::load(path).map_err(value_error)?;
+        return Ok(Self { trees });
+    }
+
+    /// Build from a list of JSON encoded lineages.
+    #[staticmethod]
+    fn from_json(lines: Vec<String>) -> PyResult<Self> {
+        let mut trees = lca::TreeInfo::default();
+        for line in lines {
+            let mapping =
+                serde_json::from_str(&line).map_err(|e| PyValueError::new_err(e.to_string()))?;
+            trees.insert(mapping);
+        }
+        return Ok(Self { trees });
+    }
+
+    /// Find the lowest common ancestor of a sequence and the model used to
+    /// draw it, as a dict with the same fields as the `lca` command output.
+    fn lca(
+        &self,
+        py: Python,
+        urs: UrsId,
+        model_name: String,
+        sequence_taxid: usize,
+        model_taxid: usize,
+    ) -> PyResult<PyObject> {
+        let assignment = lca::DiagramAssignment {
+            urs,
+            model_name,
+            sequence_taxid,
+            model_taxid,
+        };
+        let result = lca::lca(&self.trees, assignment).map_err(value_error)?;
+        let rank = serialized_name(&result.ancestor_rank).map_err(value_error)?;
+        let status = serialized_name(&result.status).map_err(value_error)?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("urs", result.urs.to_string())?;
+        dict.set_item("taxid", result.taxid)?;
+        dict.set_item("model_name", result.model_name)?;
+        dict.set_item("status", status)?;
+        dict.set_item("ancestor_rank", rank)?;
+        dict.set_item("ancestor_taxid", result.ancestor_taxid)?;
+        dict.set_item("ancestor_name", result.ancestor_name)?;
+        dict.set_item("rank_distance", result.rank_distance)?;
+        return Ok(dict.into());
+    }
+}
+
+#[pymodule]
+fn r2dt_utils(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(path_for, m)?)?;
+    m.add_function(wrap_pyfunction!(directory_path, m)?)?;
+    m.add_function(wrap_pyfunction!(looks_like_urs, m)?)?;
+    m.add_function(wrap_pyfunction!(filename_urs, m)?)?;
+    m.add_function(wrap_pyfunction!(urs_to_index, m)?)?;
+    m.add_function(wrap_pyfunction!(int_to_urs, m)?)?;
+    m.add_function(wrap_pyfunction!(count_svg, m)?)?;
+    m.add_function(wrap_pyfunction!(count_file, m)?)?;
+    m.add_class::<coloring::Counts>()?;
+    m.add_class::<PyTreeInfo>()?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::lineage;
+
+    #[test]
+    fn builds_paths_by_taxid() -> Result<()> {
+        let urs: UrsId = "URS0000000372_9606".parse()?;
+        let base = PathBuf::from("svgs");
+        assert_eq!(
+            with_taxid(Layout::default(), false).path_for(&base, &urs, None),
+            PathBuf::from("svgs/URS/00/00/00/03/URS0000000372_9606.svg.gz")
+        );
+        assert_eq!(
+            with_taxid("flat,uncompressed".parse()?, true).path_for(&base, &urs, None),
+            PathBuf::from("svgs/9606/URS0000000372_9606.svg")
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn counts_svg_text() -> Result<()> {
+        let urs: UrsId = "URS0000000372".parse()?;
+        let svg = r#"<svg>
+            <text class="green">A</text>
+            <text class="black">N</text>
+        </svg>"#;
+        assert_eq!(count_text(urs, svg, "iupac")?.total, 2);
+        assert_eq!(count_text(urs, svg, "ACGU")?.total, 1);
+        assert!(count_text(urs, svg, "AC-GU").is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn names_lca_fields() -> Result<()> {
+        let mut human = lineage::Mapping::empty(9606);
+        human.lineage = vec![lineage::LineageTaxon {
+            name: String::from("Homo sapiens"),
+            taxid: 9606,
+            rank: Some(String::from("species")),
+        }];
+        let trees: lca::TreeInfo = vec![human, lineage::Mapping::deleted(12)]
+            .into_iter()
+            .collect();
+        let assignment = |sequence_taxid| lca::DiagramAssignment {
+            urs: "URS0000000372".parse().unwrap(),
+            model_name: String::from("RF00001"),
+            sequence_taxid,
+            model_taxid: 9606,
+        };
+
+        let found = lca::lca(&trees, assignment(9606))?;
+        assert_eq!(serialized_name(&found.status)?, Some(String::from("found")));
+        assert_eq!(
+            serialized_name(&found.ancestor_rank)?,
+            Some(String::from("Species"))
+        );
+
+        let deleted = lca::lca(&trees, assignment(12))?;
+        assert_eq!(
+            serialized_name(&deleted.status)?,
+            Some(String::from("deleted_taxid"))
+        );
+        assert_eq!(serialized_name(&deleted.ancestor_rank)?, None);
+        return Ok(());
+    }
+}
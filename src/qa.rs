@@ -0,0 +1,212 @@
+//! Join the metadata R2DT produces about diagrams with their color counts, to
+//! find diagrams which need checking by hand.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use anyhow::{anyhow, Result};
+
+use crate::coloring::score::{self, Grade, Policy};
+use crate::coloring::{self, Alphabet, Counts, Failures};
+use crate::results::{self, Metadata};
+use crate::urs::UrsId;
+
+/// Where the color counts come from.
+pub enum CountSource {
+    /// A CSV produced by `coloring tree` or `coloring json-file`.
+    Load(PathBuf),
+    /// Count the diagrams in a tree with the given number of threads and
+    /// alphabet, recording any which cannot be parsed in `keep_going`, if
+    /// given, instead of stopping.
+    Compute {
+        tree: PathBuf,
+        jobs: usize,
+        alphabet: Alphabet,
+        keep_going: Option<PathBuf>,
+    },
+}
+
+/// The limits used to flag diagrams.
+pub struct QaOptions {
+    pub max_overlap: u64,
+    pub min_coverage: f64,
+    /// The limits used to grade the color counts, as in `coloring score`.
+    pub policy: Policy,
+}
+
+/// One row of the QA table. Count and grade columns are empty if the diagram
+/// has no counts.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QaRow {
+    pub urs: UrsId,
+    pub model_name: String,
+    pub overlap_count: u64,
+    pub basepair_count: u64,
+    pub sequence_coverage: Option<f64>,
+    pub total: Option<u64>,
+    pub changed: Option<u64>,
+    pub inserted: Option<u64>,
+    pub moved: Option<u64>,
+    pub rotated: Option<u64>,
+    pub fraction_inserted: Option<f64>,
+    pub grade: Option<Grade>,
+    /// Every limit of the policy the counts are outside of.
+    pub reasons: Option<String>,
+    pub high_overlap: bool,
+    pub low_coverage: bool,
+    pub missing_counts: bool,
+}
+
+impl QaOptions {
+    /// Join the metadata and counts of a diagram and flag any problems.
+    pub fn row(&self, metadata: Metadata, counts: Option<&Counts>) -> QaRow {
+        let score = counts.map(|c| score::score(c, None, &self.policy));
+        return QaRow {
+            high_overlap: metadata.overlap_count > self.max_overlap,
+            low_coverage: metadata
+                .sequence_coverage
+                .is_some_and(|c| c < self.min_coverage),
+            missing_counts: counts.is_none(),
+            urs: metadata.urs,
+            model_name: metadata.model_name,
+            overlap_count: metadata.overlap_count,
+            basepair_count: metadata.basepair_count,
+            sequence_coverage: metadata.sequence_coverage,
+            total: counts.map(|c| c.total),
+            changed: counts.map(|c| c.changed),
+            inserted: counts.map(|c| c.inserted),
+            moved: counts.map(|c| c.moved),
+            rotated: counts.map(|c| c.rotated),
+            fraction_inserted: score.as_ref().map(|s| s.fraction_inserted),
+            grade: score.as_ref().map(|s| s.grade),
+            reasons: score.map(|s| s.reasons),
+        };
+    }
+}
+
+impl QaRow {
+    pub fn is_flagged(&self) -> bool {
+        return self.high_overlap
+            || self.low_coverage
+            || self.grade.is_some_and(|g| g != Grade::Pass);
+    }
+}
+
+fn load_counts(source: CountSource) -> Result<HashMap<UrsId, Counts>> {
+    let mut counts = HashMap::new();
+    match source {
+        CountSource::Load(path) => {
+            let mut reader = csv::Reader::from_path(path)?;
+            for record in reader.deserialize() {
+                let record: Counts = record?;
+                counts.insert(record.urs, record);
+            }
+        }
+        CountSource::Compute {
+            tree,
+            jobs,
+            alphabet,
+            keep_going,
+        } => {
+            let mut failures = Failures::new(&keep_going)?;
+            for record in coloring::tree_counts(tree, jobs, alphabet)? {
+                match record {
+                    Ok(record) => {
+                        counts.insert(record.urs, record);
+                    }
+                    Err(error) => failures.record(error)?,
+                }
+            }
+            failures.finish()?;
+        }
+    }
+    return Ok(counts);
+}
+
+/// Write a QA table to `out` as CSV, with a row for every diagram in the
+/// metadata file.
+pub fn write_qa<W: Write>(
+    metadata_file: PathBuf,
+    source: CountSource,
+    options: &QaOptions,
+    out: W,
+) -> Result<()> {
+    let counts = load_counts(source)?;
+    if counts.is_empty() {
+        return Err(anyhow!("No color counts were found"));
+    }
+
+    let mut writer = csv::Writer::from_writer(out);
+    let mut flagged = 0;
+    let mut missing = 0;
+    for metadata in results::metadata(metadata_file)? {
+        let metadata = metadata?;
+        let found = counts.get(&metadata.urs);
+        let row = options.row(metadata, found);
+        if row.is_flagged() {
+            flagged += 1;
+        }
+        if row.missing_counts {
+            missing += 1;
+        }
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    log::info!(
+        "Flagged {} diagrams, {} had no color counts",
+        flagged,
+        missing
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_poor_diagrams() -> Result<()> {
+        let options = QaOptions {
+            max_overlap: 5,
+            min_coverage: 0.8,
+            policy: Policy::default(),
+        };
+        let urs: UrsId = "URS0000000372".parse()?;
+        let metadata = || Metadata {
+            urs,
+            secondary_structure: String::from("((..))"),
+            overlap_count: 10,
+            basepair_count: 2,
+            model_start: None,
+            model_stop: None,
+            sequence_start: None,
+            sequence_stop: None,
+            sequence_coverage: Some(0.9),
+            model_name: String::from("RF00001"),
+        };
+        let counts = Counts {
+            urs,
+            unchanged: 7,
+            inserted: 2,
+            rotated: 1,
+            total: 10,
+            ..Default::default()
+        };
+
+        let row = options.row(metadata(), Some(&counts));
+        assert!(row.high_overlap && row.is_flagged());
+        assert!(!row.low_coverage && !row.missing_counts);
+        assert_eq!(row.fraction_inserted, Some(0.2));
+        assert_eq!((row.moved, row.rotated), (Some(0), Some(1)));
+        assert_eq!(row.grade, Some(Grade::Warn));
+        assert_eq!(row.reasons.as_deref(), Some("inserted 0.200 > 0.1"));
+
+        let row = options.row(metadata(), None);
+        assert!(row.missing_counts && row.grade.is_none());
+        assert_eq!(row.total, None);
+        return Ok(());
+    }
+}
@@ -1,11 +1,15 @@
+//! Place the diagrams produced by R2DT into their final location, and handle
+//! the metadata about them.
+
 use std::collections::HashMap;
 use std::fs::read_to_string;
-use std::fs::File;
+use std::fs::{create_dir_all, File};
 use std::io::prelude::*;
-use std::io::{stdout, BufReader};
-use std::path::PathBuf;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
+use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
@@ -16,37 +20,49 @@ use anyhow::Result;
 use walkdir::WalkDir;
 
 use crate::fixups::urs_utils;
+use crate::fs;
+use crate::layout::Layout;
+use crate::manifest;
+use crate::urs::UrsId;
 
+/// A diagram as produced by R2DT, with the SVG stored as text.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JsonDiagram {
-    pub urs: String,
+    pub urs: UrsId,
     pub svg: String,
+    /// The model used to draw the diagram, needed for the model family
+    /// layout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_name: Option<String>,
 }
 
+/// An SVG file found in an R2DT result directory.
 #[derive(Debug)]
-struct DiagramSvg {
-    urs: String,
-    path: PathBuf,
+pub struct DiagramSvg {
+    pub urs: UrsId,
+    pub model_name: Option<String>,
+    pub path: PathBuf,
 }
 
 #[derive(Debug, Deserialize)]
-struct UrsRename {
-    old_urs: String,
-    new_urs: String,
+pub struct UrsRename {
+    pub old_urs: UrsId,
+    pub new_urs: UrsId,
 }
 
+/// The metadata R2DT produces about each diagram.
 #[derive(Debug, Deserialize, Serialize)]
-struct Metadata {
-    urs: String,
-    secondary_structure: String,
-    overlap_count: u64,
-    basepair_count: u64,
-    model_start: Option<u64>,
-    model_stop: Option<u64>,
-    sequence_start: Option<u64>,
-    sequence_stop: Option<u64>,
-    sequence_coverage: Option<f64>,
-    model_name: String,
+pub struct Metadata {
+    pub urs: UrsId,
+    pub secondary_structure: String,
+    pub overlap_count: u64,
+    pub basepair_count: u64,
+    pub model_start: Option<u64>,
+    pub model_stop: Option<u64>,
+    pub sequence_start: Option<u64>,
+    pub sequence_stop: Option<u64>,
+    pub sequence_coverage: Option<f64>,
+    pub model_name: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -54,9 +70,34 @@ struct Cdmi {
     value: String,
 }
 
-enum Renamer {
+/// Decides the final URS a diagram is stored under.
+pub enum Renamer {
     NoRename,
-    UseMapping(HashMap<String, String>),
+    UseMapping(HashMap<UrsId, UrsId>),
+}
+
+pub struct WriteOptions {
+    pub layout: Layout,
+    /// Leave diagrams whose content has not changed untouched.
+    pub incremental: bool,
+}
+
+/// What writing a single diagram did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    New,
+    Updated,
+    Unchanged,
+    Unmapped,
+}
+
+/// The number of diagrams with each outcome.
+#[derive(Debug, Default)]
+pub struct WriteSummary {
+    pub new: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub unmapped: usize,
 }
 
 pub struct TransferOptions {
@@ -67,6 +108,8 @@ pub struct TransferOptions {
 }
 
 impl Renamer {
+    /// Load a CSV file with `old_urs` and `new_urs` columns, or do no renaming
+    /// if no file is given.
     pub fn new(filename: Option<PathBuf>) -> Result<Self> {
         return match filename {
             None => Ok(Self::NoRename),
@@ -85,14 +128,36 @@ impl Renamer {
         };
     }
 
-    pub fn rename(&self, urs: &String) -> Option<String> {
+    /// The new URS for `urs`, or `None` if the mapping does not contain it.
+    pub fn rename(&self, urs: &UrsId) -> Option<UrsId> {
         return match self {
-            Self::NoRename => Some(urs.to_string()),
-            Self::UseMapping(mapping) => mapping.get(urs).map(|s| s.to_string()),
+            Self::NoRename => Some(*urs),
+            Self::UseMapping(mapping) => mapping.get(urs).cloned(),
         };
     }
 }
 
+impl WriteSummary {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::New => self.new += 1,
+            Outcome::Updated => self.updated += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Unmapped => self.unmapped += 1,
+        }
+    }
+
+    pub fn log(&self) {
+        log::info!(
+            "Wrote {} new and {} updated diagrams, {} were unchanged and {} had no mapping",
+            self.new,
+            self.updated,
+            self.unchanged,
+            self.unmapped
+        );
+    }
+}
+
 impl Cdmi {
     pub fn from_path(path: &PathBuf) -> Result<Self> {
         let mut buf = Vec::new();
@@ -112,23 +177,72 @@ impl TransferOptions {
     }
 }
 
-fn write(diagram: &JsonDiagram, renamer: &Renamer, base: &PathBuf) -> Result<()> {
+/// Check if the diagram at `path` has exactly the given content. Unreadable
+/// files are treated as different.
+fn has_content(path: &Path, svg: &str, compressed: bool) -> bool {
+    let file = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return false,
+    };
+    let existing = match compressed {
+        true => manifest::digest(GzDecoder::new(file)),
+        false => manifest::digest(file),
+    };
+    return match (existing, manifest::digest(svg.as_bytes())) {
+        (Ok(existing), Ok(new)) => existing == new,
+        (Err(e), _) | (_, Err(e)) => {
+            log::debug!("Could not read {:?}: {}", path, e);
+            false
+        }
+    };
+}
+
+/// Write a diagram to its final location in the tree under `base`, compressing
+/// it if the layout requires. In incremental mode diagrams which already exist
+/// with the same content are not rewritten.
+pub fn write(
+    diagram: &JsonDiagram,
+    renamer: &Renamer,
+    base: &Path,
+    options: &WriteOptions,
+) -> Result<Outcome> {
     let urs = renamer.rename(&diagram.urs);
     if urs.is_none() {
-        log::error!("Could not find renamed URS for {:?}", urs);
-        return Ok(());
+        log::error!("Could not find renamed URS for {}", &diagram.urs);
+        return Ok(Outcome::Unmapped);
     };
     let urs = urs.unwrap();
     log::info!("Renaming {} to {}", &diagram.urs, &urs);
-    let path = urs_utils::path_for(base, &urs);
+    let layout = &options.layout;
+    let path = layout.path_for(base, &urs, diagram.model_name.as_deref());
+    let existed = path.exists();
+    if options.incremental && existed && has_content(&path, &diagram.svg, layout.compressed) {
+        log::debug!("Skipping unchanged {:?}", &path);
+        return Ok(Outcome::Unchanged);
+    }
+
     log::info!("Writing to {:?}", &path);
-    let out_file = File::create(path)?;
-    let mut gz = GzEncoder::new(out_file, Compression::default());
-    gz.write_all(&diagram.svg.as_ref())?;
-    return Ok(());
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    fs::write_atomically(&path, |file| {
+        if layout.compressed {
+            let mut gz = GzEncoder::new(file, Compression::default());
+            gz.write_all(diagram.svg.as_ref())?;
+            gz.finish()?;
+        } else {
+            file.write_all(diagram.svg.as_ref())?;
+        }
+        return Ok(());
+    })?;
+    return match existed {
+        true => Ok(Outcome::Updated),
+        false => Ok(Outcome::New),
+    };
 }
 
-fn svgs(directory: PathBuf) -> Result<Vec<DiagramSvg>> {
+/// Find all SVGs with a URS in their filename in a directory.
+pub fn svgs(directory: PathBuf) -> Result<Vec<DiagramSvg>> {
     let mut svgs = Vec::new();
     for filename in WalkDir::new(directory) {
         log::debug!("Found file: {:?}", &filename);
@@ -145,7 +259,12 @@ fn svgs(directory: PathBuf) -> Result<Vec<DiagramSvg>> {
             continue;
         }
         let urs = urs.unwrap();
-        svgs.push(DiagramSvg { urs, path });
+        let model_name = urs_utils::filename_model(&path);
+        svgs.push(DiagramSvg {
+            urs,
+            model_name,
+            path,
+        });
     }
     return Ok(svgs);
 }
@@ -154,10 +273,12 @@ pub fn move_file(
     filename: PathBuf,
     target_directory: PathBuf,
     mapping_file: Option<PathBuf>,
+    options: &WriteOptions,
 ) -> Result<()> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
     let renamer = Renamer::new(mapping_file)?;
+    let mut summary = WriteSummary::default();
 
     for line in reader.lines() {
         let line = line?;
@@ -168,11 +289,13 @@ pub fn move_file(
             let json = JsonDiagram {
                 urs: diagram.urs,
                 svg: svg_text,
+                model_name: diagram.model_name,
             };
-            write(&json, &renamer, &target_directory)?;
+            summary.record(write(&json, &renamer, &target_directory, options)?);
         }
     }
 
+    summary.log();
     return Ok(());
 }
 
@@ -180,27 +303,38 @@ pub fn split_file(
     filename: PathBuf,
     target_directory: PathBuf,
     mapping_file: Option<PathBuf>,
+    options: &WriteOptions,
 ) -> Result<()> {
     let file = File::open(filename)?;
     let file = BufReader::new(file);
     let renamer = Renamer::new(mapping_file)?;
+    let mut summary = WriteSummary::default();
     for line in file.lines() {
         let line = line?.replace("\\\\", "\\");
         let entry: JsonDiagram = serde_json::from_str(&line)?;
-        write(&entry, &renamer, &target_directory)?;
+        summary.record(write(&entry, &renamer, &target_directory, options)?);
     }
+    summary.log();
     return Ok(());
 }
 
-pub fn rename_metadata(mapping_file: PathBuf, filename: PathBuf) -> Result<()> {
-    let renamer = Renamer::new(Some(mapping_file))?;
+/// Read a CSV file of diagram metadata.
+pub fn metadata(filename: PathBuf) -> Result<impl Iterator<Item = Result<Metadata>>> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
-    let mut reader = csv::Reader::from_reader(reader);
-    let mut writer = csv::Writer::from_writer(stdout());
+    let reader = csv::Reader::from_reader(reader);
+    return Ok(reader
+        .into_deserialize()
+        .map(|r| r.map_err(anyhow::Error::from)));
+}
+
+/// Write the metadata with every URS renamed to `out` as CSV.
+pub fn rename_metadata<W: Write>(mapping_file: PathBuf, filename: PathBuf, out: W) -> Result<()> {
+    let renamer = Renamer::new(Some(mapping_file))?;
+    let mut writer = csv::Writer::from_writer(out);
     let mut unmapped = 0;
-    for record in reader.deserialize() {
-        let record: Metadata = record?;
+    for record in metadata(filename)? {
+        let record = record?;
         let urs = renamer.rename(&record.urs);
         if urs.is_none() {
             log::error!("Could not find renamed URS for {:?}", &record.urs);
@@ -218,6 +352,8 @@ pub fn rename_metadata(mapping_file: PathBuf, filename: PathBuf) -> Result<()> {
     return Ok(());
 }
 
+// `is_multiple_of` is newer than the oldest compiler this builds with.
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
 pub fn transfer_svgs(filename: &PathBuf, options: TransferOptions) -> Result<()> {
     let file = File::open(filename)?;
     let file = BufReader::new(file);
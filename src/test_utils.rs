@@ -0,0 +1,65 @@
+//! Helpers shared by the tests of several modules.
+
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::Result;
+
+use crate::fixups::CheckOptions;
+
+/// A directory for the files of a single test, removed when it is dropped so
+/// failing tests clean up as well.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create an empty directory named after the test in the system
+    /// temporary directory.
+    pub fn new(name: &str) -> Result<Self> {
+        return Self::within(&std::env::temp_dir(), name);
+    }
+
+    /// Create an empty directory named after the test inside `parent`, which
+    /// may be a relative path.
+    pub fn within(parent: &Path, name: &str) -> Result<Self> {
+        let path = parent.join(format!("r2dt-{}-{}", name, process::id()));
+        if path.exists() {
+            remove_dir_all(&path)?;
+        }
+        create_dir_all(&path)?;
+        return Ok(Self { path });
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// Write `content` to the file `name` in this directory, creating any
+    /// directories it needs.
+    pub fn write(&self, name: &str, content: &str) -> Result<PathBuf> {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&path, content)?;
+        return Ok(path);
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.path);
+    }
+}
+
+/// Options for a shallow check of a tree in the given layout.
+pub fn check_options(layout: &str) -> Result<CheckOptions> {
+    return Ok(CheckOptions {
+        layout: layout.parse()?,
+        deep: false,
+        check_gzip: false,
+        exclude: Vec::new(),
+    });
+}
@@ -0,0 +1,233 @@
+//! Validated URS identifiers, with or without a taxid.
+
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+use anyhow::{anyhow, Result};
+
+/// The largest index which can be written as a URS.
+pub const MAX_INDEX: u64 = 0xFF_FFFF_FFFF;
+
+/// A URS id, such as `URS0000C0472E`. This is stored as the index the hex
+/// digits encode, so ordering and arithmetic follow the numeric value.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Urs(u64);
+
+impl Urs {
+    pub fn from_index(index: u64) -> Result<Self> {
+        return match index <= MAX_INDEX {
+            true => Ok(Self(index)),
+            false => Err(anyhow!("Index {} is too large for a URS", index)),
+        };
+    }
+
+    pub fn index(&self) -> u64 {
+        return self.0;
+    }
+
+    pub fn checked_add(self, offset: u64) -> Option<Self> {
+        return self
+            .0
+            .checked_add(offset)
+            .filter(|i| *i <= MAX_INDEX)
+            .map(Self);
+    }
+
+    /// All URS from `start` up to, but not including, `stop`.
+    pub fn range(start: Urs, stop: Urs) -> impl DoubleEndedIterator<Item = Urs> {
+        return (start.0..stop.0).map(Self);
+    }
+}
+
+impl FromStr for Urs {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let digits = raw
+            .strip_prefix("URS")
+            .filter(|d| d.len() == 10)
+            .filter(|d| {
+                d.chars()
+                    .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid URS '{}', expected URS followed by 10 uppercase hex digits",
+                    raw
+                )
+            })?;
+        return Ok(Self(u64::from_str_radix(digits, 16)?));
+    }
+}
+
+impl fmt::Display for Urs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "URS{:010X}", self.0);
+    }
+}
+
+impl Serialize for Urs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.collect_str(self);
+    }
+}
+
+impl<'de> Deserialize<'de> for Urs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        return raw.trim().parse().map_err(de::Error::custom);
+    }
+}
+
+impl Add<u64> for Urs {
+    type Output = Urs;
+
+    fn add(self, offset: u64) -> Urs {
+        return self.checked_add(offset).expect("URS index overflow");
+    }
+}
+
+impl Sub<Urs> for Urs {
+    type Output = u64;
+
+    /// The number of URS between the two ids.
+    fn sub(self, other: Urs) -> u64 {
+        return self.0 - other.0;
+    }
+}
+
+/// A URS, optionally suffixed with the taxid of a single organism, such as
+/// `URS0000000001_9606`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UrsId {
+    urs: Urs,
+    taxid: Option<usize>,
+}
+
+impl UrsId {
+    pub fn new(urs: Urs, taxid: Option<usize>) -> Self {
+        return Self { urs, taxid };
+    }
+
+    pub fn urs(&self) -> Urs {
+        return self.urs;
+    }
+
+    pub fn taxid(&self) -> Option<usize> {
+        return self.taxid;
+    }
+}
+
+impl From<Urs> for UrsId {
+    fn from(urs: Urs) -> Self {
+        return Self::new(urs, None);
+    }
+}
+
+impl FromStr for UrsId {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        return match raw.split_once('_') {
+            None => Ok(Self::from(raw.parse::<Urs>()?)),
+            Some((urs, taxid)) => {
+                let taxid = match taxid.chars().all(|c| c.is_ascii_digit()) {
+                    true => taxid.parse::<usize>().ok(),
+                    false => None,
+                }
+                .ok_or_else(|| anyhow!("Invalid taxid '{}' in '{}'", taxid, raw))?;
+                Ok(Self::new(urs.parse()?, Some(taxid)))
+            }
+        };
+    }
+}
+
+impl fmt::Display for UrsId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self.taxid {
+            None => write!(f, "{}", self.urs),
+            Some(taxid) => write!(f, "{}_{}", self.urs, taxid),
+        };
+    }
+}
+
+impl Serialize for UrsId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.collect_str(self);
+    }
+}
+
+impl<'de> Deserialize<'de> for UrsId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        return raw.trim().parse().map_err(de::Error::custom);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats() -> Result<()> {
+        let urs: Urs = "URS0000C0472E".parse()?;
+        assert_eq!(urs.index(), 12601134);
+        assert_eq!(urs.to_string(), "URS0000C0472E");
+        assert_eq!(Urs::from_index(1)?.to_string(), "URS0000000001");
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        assert!("URS00000001AAB82D".parse::<Urs>().is_err());
+        assert!("URS0000c0472e".parse::<Urs>().is_err());
+        assert!("URS000".parse::<Urs>().is_err());
+        assert!("0000C0472E".parse::<Urs>().is_err());
+        assert!("URS0000+00001".parse::<Urs>().is_err());
+        assert!(Urs::from_index(MAX_INDEX + 1).is_err());
+    }
+
+    #[test]
+    fn supports_arithmetic_and_ranges() -> Result<()> {
+        let start: Urs = "URS00000000FF".parse()?;
+        assert_eq!((start + 1).to_string(), "URS0000000100");
+        assert_eq!((start + 1) - start, 1);
+        assert!(start < start + 1);
+        let ids: Vec<String> = Urs::range(start, start + 3)
+            .map(|u| u.to_string())
+            .collect();
+        assert_eq!(ids, vec!["URS00000000FF", "URS0000000100", "URS0000000101"]);
+        assert_eq!(Urs::from_index(MAX_INDEX)?.checked_add(1), None);
+        return Ok(());
+    }
+
+    #[test]
+    fn round_trips_through_serde() -> Result<()> {
+        let urs: Urs = serde_json::from_str("\"URS0000000372\"")?;
+        assert_eq!(serde_json::to_string(&urs)?, "\"URS0000000372\"");
+        assert!(serde_json::from_str::<Urs>("\"URS1\"").is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn parses_taxid_suffixed_ids() -> Result<()> {
+        let id: UrsId = "URS0000000001_9606".parse()?;
+        assert_eq!(id.urs().to_string(), "URS0000000001");
+        assert_eq!(id.taxid(), Some(9606));
+        assert_eq!(id.to_string(), "URS0000000001_9606");
+
+        let plain: UrsId = "URS0000000001".parse()?;
+        assert_eq!(plain.taxid(), None);
+        assert_eq!(plain.to_string(), "URS0000000001");
+
+        assert!("URS0000000001_".parse::<UrsId>().is_err());
+        assert!("URS0000000001_+9606".parse::<UrsId>().is_err());
+        assert!("URS0000000001_9606_1".parse::<UrsId>().is_err());
+        assert!("URS1_9606".parse::<UrsId>().is_err());
+        return Ok(());
+    }
+}